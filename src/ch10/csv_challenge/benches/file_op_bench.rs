@@ -1,11 +1,12 @@
 #![feature(test)]
 extern crate test;
 use test::Bencher;
+use std::io;
 use std::path::PathBuf;
 use csv_challenge::{
-    Opt,
-    {load_csv, write_csv},
+    {load_csv, write_csv, open_csv, create_csv},
     replace_column,
+    {transform_stream, transform_parallel, Set, Writer},
 };
 #[bench]
 fn bench_read_100times(b: &mut Bencher) {
@@ -16,7 +17,7 @@ fn bench_read_100times(b: &mut Bencher) {
 }
 fn test_load_csv(){
     let filename = PathBuf::from("./input/challenge.csv");
-    load_csv(filename);
+    load_csv(filename).unwrap();
 }
 #[bench]
 fn bench_rw_100times(b: &mut Bencher) {
@@ -30,5 +31,47 @@ fn test_read_write_csv(){
     let filename = PathBuf::from("./input/challenge.csv");
     let csv_data = load_csv(filename).unwrap();
     let modified_data = replace_column(csv_data, "City", "Beijing").unwrap();
-    write_csv(&modified_data, "output/test.csv");
+    write_csv(&modified_data, "output/test.csv").unwrap();
+}
+
+/// Writes a file of `rows` records, some with quoted line breaks, once.
+fn large_csv(rows: usize) -> PathBuf {
+    let filename = std::env::temp_dir().join(format!("csv_challenge_bench_{}.csv", rows));
+    if !filename.exists() {
+        let mut writer = create_csv(filename.to_str().unwrap()).unwrap();
+        writer.write_record(&["First Name", "Last Name", "Age", "City", "Note"]).unwrap();
+        for i in 0..rows {
+            let age = (i % 90).to_string();
+            let note = if i % 10 == 0 { "line\nbreak" } else { "plain" };
+            writer.write_record(&[&format!("John{}", i), "Doe", &age, "Tokyo", note]).unwrap();
+        }
+        writer.flush().unwrap();
+    }
+    filename
+}
+
+fn replace_large(jobs: usize) {
+    let reader = open_csv(large_csv(100_000)).unwrap();
+    let mut writer = Writer::new(io::sink());
+    let mut set = Set::new("City", "Beijing");
+    if jobs > 1 {
+        transform_parallel(reader, &mut writer, &mut set, jobs).unwrap();
+    } else {
+        transform_stream(reader, &mut writer, &mut set).unwrap();
+    }
+}
+
+#[bench]
+fn bench_replace_large_1_job(b: &mut Bencher) {
+    b.iter(|| replace_large(1));
+}
+
+#[bench]
+fn bench_replace_large_2_jobs(b: &mut Bencher) {
+    b.iter(|| replace_large(2));
+}
+
+#[bench]
+fn bench_replace_large_4_jobs(b: &mut Bencher) {
+    b.iter(|| replace_large(4));
 }
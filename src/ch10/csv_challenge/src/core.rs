@@ -1,8 +1,31 @@
 pub mod read;
+pub mod parse;
+pub mod dialect;
+pub mod ragged;
+pub mod transform;
+pub mod record;
+pub mod table;
+pub mod replace;
+pub mod filter;
+pub mod format;
+pub mod json;
 pub mod write;
+pub mod stats;
+pub mod sort;
+pub mod join;
+pub mod group;
+pub mod validate;
+pub mod diff;
+pub mod parallel;
+pub mod encoding;
+pub mod compress;
+pub mod expr;
+pub mod plan;
+pub mod split;
+pub mod cat;
 use crate::err::Error;
 use std::{
   path::PathBuf,
   fs::File,
-  io::{Read, Write},
+  io::Write,
 };
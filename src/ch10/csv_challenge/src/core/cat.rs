@@ -0,0 +1,115 @@
+use super::Error;
+use super::format::Output;
+use super::parse::Source;
+
+/// Concatenates inputs whose columns may differ, aligning them by header
+/// name. The output has every column of the inputs, in the order first
+/// seen, and fills the ones an input lacks.
+///
+/// Inputs are opened twice, once to read every header and once for the
+/// records, so only one is open at a time.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Cat, Writer};
+/// let inputs = ["Name,City\nJohn,Tokyo\n", "City,Age\nParis,12\n"];
+/// let mut writer = Writer::new(Vec::new());
+/// let records = Cat::new().cat(inputs.len(), |i| Ok(parse_records(inputs[i])), &mut writer).unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!((records, output.as_str()), (2, "Name,City,Age\nJohn,Tokyo,\n,Paris,12\n"));
+/// ```
+#[derive(Default)]
+pub struct Cat {
+    fill: String,
+}
+
+impl Cat {
+    pub fn new() -> Self {
+        Cat::default()
+    }
+
+    /// The value of missing columns, empty by default.
+    pub fn fill(mut self, value: &str) -> Self {
+        self.fill = value.to_string();
+        self
+    }
+
+    /// Writes the records of the `count` inputs `open` opens to `output`,
+    /// returning how many there were.
+    pub fn cat<S, F, O>(&self, count: usize, mut open: F, output: &mut O) -> Result<u64, Error>
+    where
+        S: Source,
+        F: FnMut(usize) -> Result<S, Error>,
+        O: Output + ?Sized,
+    {
+        let mut columns: Vec<String> = Vec::new();
+        let mut synthetic = true;
+        for i in 0..count {
+            let mut input = open(i)?;
+            let headers = input.headers()?;
+            synthetic &= input.synthetic_headers();
+            // A name repeated in one input is matched by occurrence.
+            for (j, name) in headers.iter().enumerate() {
+                let seen = headers[..j].iter().filter(|&h| h == name).count();
+                if columns.iter().filter(|&c| c == name).count() <= seen {
+                    columns.push(name.clone());
+                }
+            }
+        }
+        output.write_headers(&columns, synthetic && count > 0)?;
+
+        let mut records = 0;
+        let mut aligned = Vec::with_capacity(columns.len());
+        for i in 0..count {
+            let mut input = open(i)?;
+            let headers = input.headers()?;
+            let targets: Vec<usize> = headers
+                .iter()
+                .enumerate()
+                .map(|(j, name)| {
+                    let seen = headers[..j].iter().filter(|&h| h == name).count();
+                    let mut found = columns.iter().enumerate().filter(|&(_, c)| c == name).map(|(k, _)| k);
+                    found.nth(seen).unwrap_or_default()
+                })
+                .collect();
+            while let Some(record) = input.read_record()? {
+                aligned.clear();
+                aligned.resize(columns.len(), self.fill.clone());
+                for (value, &target) in record.into_iter().zip(&targets) {
+                    aligned[target] = value;
+                }
+                output.write_record(&aligned)?;
+                records += 1;
+            }
+        }
+        output.finish()?;
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cat;
+    use crate::core::parse::{parse_records, Writer};
+
+    fn cat(cat: Cat, inputs: &[&str]) -> String {
+        let mut writer = Writer::new(Vec::new());
+        cat.cat(inputs.len(), |i| Ok(parse_records(inputs[i])), &mut writer).unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_align_by_header() {
+        let inputs = ["Id,A,A\n1,x,y\n", "A,Id\n2,z\n", "B\n3\n", "Id\n"];
+        assert_eq!(cat(Cat::new().fill("-"), &inputs), "Id,A,A,B\n1,x,y,-\nz,2,-,-\n-,-,-,3\n");
+        assert_eq!(cat(Cat::new(), &["A,B\n1,2\n", "A,B\n3,4\n"]), "A,B\n1,2\n3,4\n");
+    }
+
+    #[test]
+    fn test_without_header() {
+        let mut writer = Writer::new(Vec::new());
+        let inputs = ["1,2\n", "3\n"];
+        Cat::new().cat(2, |i| Ok(parse_records(inputs[i]).no_header()), &mut writer).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "1,2\n3,\n");
+    }
+}
@@ -0,0 +1,268 @@
+use super::{Error, File, Write};
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+use std::{
+    io::{self, BufRead, BufReader, Cursor, Read, Sink, Stdout, StdoutLock},
+    path::Path,
+};
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+/// How a file is compressed, if at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression a file name's extension stands for, `.gz` or
+    /// `.zst`.
+    pub fn from_path(path: &str) -> Compression {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("gz") | Some("gzip") => Compression::Gzip,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// The compression `sample`, the start of the input, starts with the
+    /// magic bytes of.
+    pub fn sniff(sample: &[u8]) -> Compression {
+        if sample.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if sample.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// The compression of the file at `path`, by its magic bytes.
+    pub fn detect(path: &Path) -> Result<Compression, Error> {
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        File::open(path)?.take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic)?;
+        Ok(Compression::sniff(&magic))
+    }
+
+    /// Decompresses `input` by the magic bytes it starts with, passing
+    /// anything else through. Concatenated gzip members and zstd frames
+    /// are read as one stream.
+    pub fn decompress<'a, R: BufRead + 'a>(mut input: R) -> Result<Box<dyn BufRead + 'a>, Error> {
+        // A pipe may hand out fewer bytes than the magic at a time.
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        input.by_ref().take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic)?;
+        let compression = Compression::sniff(&magic);
+        let input = Cursor::new(magic).chain(input);
+        Ok(match compression {
+            Compression::None => Box::new(input),
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(input))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(input)?)),
+        })
+    }
+
+    /// Wraps `output` to compress what is written to it. The compressed
+    /// stream is only complete once `finish` returns.
+    pub fn compress<W: Write>(self, output: W) -> Result<CompressWriter<W>, Error> {
+        Ok(CompressWriter(match self {
+            Compression::None => Compressor::None(output),
+            Compression::Gzip => Compressor::Gzip(GzEncoder::new(output, flate2::Compression::default())),
+            Compression::Zstd => Compressor::Zstd(zstd::Encoder::new(output, 0)?),
+        }))
+    }
+}
+
+/// A stream that has to be ended, not only flushed, for what was written
+/// to it to be complete, as compressed streams do.
+pub trait Finish: Write {
+    /// Writes out the end of the stream and flushes it. Nothing is to be
+    /// written after.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<F: Finish + ?Sized> Finish for &mut F {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<F: Finish + ?Sized> Finish for Box<F> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl Finish for Vec<u8> {
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Finish for Sink {
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Finish for File {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Finish for Stdout {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Finish for StdoutLock<'_> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+enum Compressor<W: Write> {
+    None(W),
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+/// Compresses what is written to it on its way to `output`, see
+/// `Compression::compress`.
+///
+/// A gzip stream dropped unfinished is finished then, ignoring errors; a
+/// zstd one is left cut short.
+pub struct CompressWriter<W: Write>(Compressor<W>);
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Compressor::None(output) => output.write(bytes),
+            Compressor::Gzip(encoder) => encoder.write(bytes),
+            Compressor::Zstd(encoder) => encoder.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Compressor::None(output) => output.flush(),
+            Compressor::Gzip(encoder) => encoder.flush(),
+            Compressor::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl<W: Write> Finish for CompressWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Compressor::None(output) => output.flush(),
+            Compressor::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            Compressor::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, Finish};
+    use std::io::{self, BufReader, Read, Write};
+
+    fn round_trip(compression: Compression, text: &str) -> (Vec<u8>, String) {
+        let path = std::env::temp_dir().join(format!("csv_challenge_{:?}_{}", compression, text.len()));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut writer = compression.compress(file).unwrap();
+        writer.write_all(text.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut decoded = String::new();
+        Compression::decompress(&bytes[..]).unwrap().read_to_string(&mut decoded).unwrap();
+        (bytes, decoded)
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let text = "City,Age\nTokyo,32\n".repeat(100);
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let (bytes, decoded) = round_trip(compression, &text);
+            assert_eq!(Compression::sniff(&bytes), compression);
+            assert_eq!(decoded, text);
+        }
+        let (_, decoded) = round_trip(Compression::Gzip, "");
+        assert_eq!(decoded, "");
+    }
+
+    #[test]
+    fn test_detection() {
+        assert_eq!(Compression::from_path("archive/2024.csv.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_path("big.tsv.ZST"), Compression::Zstd);
+        assert_eq!(Compression::from_path("input/challenge.csv"), Compression::None);
+        assert_eq!(Compression::sniff(b"\x1f"), Compression::None);
+
+        // Two gzip members, as `cat a.gz b.gz` makes.
+        let (mut first, _) = round_trip(Compression::Gzip, "a\n1\n");
+        let (second, _) = round_trip(Compression::Gzip, "2\n");
+        first.extend(second);
+        let mut decoded = String::new();
+        Compression::decompress(&first[..]).unwrap().read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "a\n1\n2\n");
+    }
+
+    /// Hands out one byte per read, as a slow pipe may.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn test_decompress_short_reads() {
+        let text = "City,Age\nTokyo,32\n";
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let (bytes, _) = round_trip(compression, text);
+            let mut decoded = String::new();
+            let input = BufReader::new(Trickle(&bytes));
+            Compression::decompress(input).unwrap().read_to_string(&mut decoded).unwrap();
+            assert_eq!(decoded, text, "{:?}", compression);
+        }
+    }
+
+    /// Accepts `limit` bytes, failing beyond as a full disk does.
+    struct Full(usize);
+
+    impl Write for Full {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if bytes.len() > self.0 {
+                return Err(io::Error::other("no space left on device"));
+            }
+            self.0 -= bytes.len();
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_finish_errors() {
+        for &compression in &[Compression::Gzip, Compression::Zstd] {
+            // The header fits, the end of the stream doesn't.
+            let mut writer = compression.compress(Full(10)).unwrap();
+            writer.write_all(b"City,Age\n").unwrap();
+            assert!(writer.finish().is_err(), "{:?}", compression);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use super::parse::Reader;
+use std::str::FromStr;
+
+/// How a quote character is written inside a quoted field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Escape {
+    /// `""` stands for one quote, as in RFC 4180.
+    Doubled,
+    /// The character escapes whatever follows it, e.g. `\"`.
+    Char(char),
+}
+
+/// The line terminator written after each record. Reading accepts both
+/// `\n` and `\r\n` unless the dialect uses `Cr`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Terminator {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+/// Which records get the whitespace around their fields removed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trim {
+    None,
+    Headers,
+    Fields,
+    All,
+}
+
+/// The flavour of CSV being read or written.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{Dialect, parse_records};
+/// let dialect = Dialect::sniff("a;b;c\n1;2;3\n");
+/// assert_eq!(dialect.delimiter, ';');
+/// let mut reader = parse_records("a;b\n1;2\n").with_dialect(dialect);
+/// assert_eq!(reader.headers().unwrap(), vec!["a", "b"]);
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dialect {
+    pub delimiter: char,
+    pub quote: char,
+    pub escape: Escape,
+    pub terminator: Terminator,
+    pub trim: Trim,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: ',',
+            quote: '"',
+            escape: Escape::Doubled,
+            terminator: Terminator::Lf,
+            trim: Trim::None,
+        }
+    }
+}
+
+const DELIMITERS: [char; 4] = [',', '\t', ';', '|'];
+
+impl Dialect {
+    pub fn tsv() -> Self {
+        Dialect { delimiter: '\t', ..Dialect::default() }
+    }
+
+    /// Guesses the dialect of `sample`, the first few kilobytes of the
+    /// input, by picking the delimiter that splits its records into the
+    /// most consistent number of fields.
+    pub fn sniff(sample: &str) -> Self {
+        let mut dialect = Dialect::default();
+        if sample.contains("\r\n") {
+            dialect.terminator = Terminator::CrLf;
+        } else if sample.contains('\r') && !sample.contains('\n') {
+            dialect.terminator = Terminator::Cr;
+        }
+        // The sample may stop in the middle of a record.
+        let end = match dialect.terminator {
+            Terminator::Cr => sample.rfind('\r'),
+            _ => sample.rfind('\n'),
+        };
+        let sample = match end {
+            Some(end) => &sample[..=end],
+            None => sample,
+        };
+        // Other quotes and escapes are only tried on evidence, so broken
+        // quoting is reported rather than explained away.
+        let mut quotes = vec!['"'];
+        if sample.contains('\'') && !sample.contains('"') {
+            quotes.push('\'');
+        }
+        let mut escapes = vec![Escape::Doubled];
+        if sample.contains('\\') {
+            escapes.push(Escape::Char('\\'));
+        }
+        let mut best = (0, 0);
+        let mut candidates = Vec::new();
+        for &delimiter in DELIMITERS.iter() {
+            for &quote in &quotes {
+                for &escape in &escapes {
+                    candidates.push(Dialect { delimiter, quote, escape, ..dialect.clone() });
+                }
+            }
+        }
+        for candidate in candidates {
+            let score = score(&candidate, sample);
+            if score > best {
+                best = score;
+                dialect = candidate;
+            }
+        }
+        dialect
+    }
+
+    /// Appends one record to `out`, quoting the fields that need it,
+    /// followed by the line terminator.
+    pub fn format_record<S: AsRef<str>>(&self, out: &mut String, fields: &[S]) {
+        // A lone empty field would make a blank line, which is skipped
+        // when read back.
+        if fields.len() == 1 && fields[0].as_ref().is_empty() {
+            out.push(self.quote);
+            out.push(self.quote);
+        }
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push(self.delimiter);
+            }
+            self.push_field(out, field.as_ref());
+        }
+        out.push_str(match self.terminator {
+            Terminator::Lf => "\n",
+            Terminator::CrLf => "\r\n",
+            Terminator::Cr => "\r",
+        });
+    }
+
+    fn push_field(&self, out: &mut String, field: &str) {
+        let escape = match self.escape {
+            Escape::Char(escape) => Some(escape),
+            Escape::Doubled => None,
+        };
+        let special = |c: char| {
+            c == self.delimiter || c == self.quote || Some(c) == escape || c == '\r' || c == '\n'
+        };
+        // Whitespace at the edges would be lost when read back trimmed.
+        let padded = self.trim != Trim::None && field.trim() != field;
+        if !padded && !field.chars().any(special) {
+            out.push_str(field);
+            return;
+        }
+        out.push(self.quote);
+        for c in field.chars() {
+            if c == self.quote || Some(c) == escape {
+                out.push(escape.unwrap_or(self.quote));
+            }
+            out.push(c);
+        }
+        out.push(self.quote);
+    }
+}
+
+/// Ranks `dialect` on `sample` by the share of records having the most
+/// common field count, then by that count. One field per record is no
+/// evidence for the delimiter at all, and a parse error ends the sample.
+fn score(dialect: &Dialect, sample: &str) -> (usize, usize) {
+    let reader = Reader::new(sample.as_bytes()).with_dialect(dialect.clone());
+    let mut counts = Vec::new();
+    let mut failed = 0;
+    for record in reader {
+        match record {
+            Ok(record) => counts.push(record.len()),
+            Err(_) => {
+                failed = 1;
+                break;
+            }
+        }
+    }
+    let mut best = (0, 0);
+    for &count in &counts {
+        let frequency = counts.iter().filter(|&&c| c == count).count();
+        if count > 1 && (frequency, count) > best {
+            best = (frequency, count);
+        }
+    }
+    match counts.len() + failed {
+        0 => (0, 0),
+        n => (best.0 * 100 / n, best.1),
+    }
+}
+
+impl FromStr for Terminator {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lf" | "\\n" => Ok(Terminator::Lf),
+            "crlf" | "\\r\\n" => Ok(Terminator::CrLf),
+            "cr" | "\\r" => Ok(Terminator::Cr),
+            _ => Err("terminator must be one of lf, crlf, cr"),
+        }
+    }
+}
+
+impl FromStr for Trim {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Trim::None),
+            "headers" => Ok(Trim::Headers),
+            "fields" => Ok(Trim::Fields),
+            "all" => Ok(Trim::All),
+            _ => Err("trim must be one of none, headers, fields, all"),
+        }
+    }
+}
+
+/// Parses a dialect character, accepting `\t` and `tab` for a tab.
+pub fn parse_char(s: &str) -> Result<char, &'static str> {
+    match s {
+        "\\t" | "tab" => return Ok('\t'),
+        "\\\\" => return Ok('\\'),
+        _ => {}
+    }
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err("expected a single character"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dialect, Escape, Terminator, Trim};
+    use crate::core::parse::parse_records;
+
+    fn parse(data: &str, dialect: &Dialect) -> Vec<Vec<String>> {
+        let reader = parse_records(data).with_dialect(dialect.clone());
+        reader.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_sniff() {
+        let semicolon = Dialect::sniff("Name;City;Note\r\nJohn;\"Tokyo; JP\";a,b\r\nFlip;Paris;c,d\r\n");
+        assert_eq!(semicolon.delimiter, ';');
+        assert_eq!(semicolon.terminator, Terminator::CrLf);
+        assert_eq!(Dialect::sniff("a\tb\n1\t2\n3\t").delimiter, '\t');
+        assert_eq!(Dialect::sniff("a|b|c\n1|2|3\n").delimiter, '|');
+        assert_eq!(Dialect::sniff("a,b\n'x,y',z\n").quote, '\'');
+        assert_eq!(Dialect::sniff("single\ncolumn\n"), Dialect::default());
+        assert_eq!(Dialect::sniff("a,b\n\"say \\\"hi\\\"\",c\n").escape, Escape::Char('\\'));
+        // Broken double quotes are not taken for single quoted input.
+        let broken = "a,b\n\"x\"y,1\n'z',2\n";
+        assert_eq!(Dialect::sniff(broken), Dialect::default());
+        assert!(parse_records(broken).with_dialect(Dialect::sniff(broken)).nth(1).unwrap().is_err());
+    }
+
+    #[test]
+    fn test_custom_dialect_round_trip() {
+        let dialect = Dialect {
+            delimiter: '|',
+            quote: '\'',
+            escape: Escape::Char('\\'),
+            terminator: Terminator::CrLf,
+            trim: Trim::None,
+        };
+        let records = vec![vec!["a|b", "it's", "back\\slash"], vec!["x", "", "line\r\nbreak"]];
+        let mut out = String::new();
+        for record in &records {
+            dialect.format_record(&mut out, record);
+        }
+        assert_eq!(out, "'a|b'|'it\\'s'|'back\\\\slash'\r\nx||'line\r\nbreak'\r\n");
+        assert_eq!(parse(&out, &dialect), records);
+    }
+
+    #[test]
+    fn test_trim() {
+        let data = " a , b \n 1 , \" 2 \" \n";
+        let fields = Dialect { trim: Trim::Fields, ..Dialect::default() };
+        assert_eq!(parse(data, &fields), vec![vec![" a ", " b "], vec!["1", " 2 "]]);
+        let all = Dialect { trim: Trim::All, ..Dialect::default() };
+        assert_eq!(parse(data, &all)[0], vec!["a", "b"]);
+        let mut out = String::new();
+        all.format_record(&mut out, &[" 2 "]);
+        assert_eq!(out, "\" 2 \"\n");
+    }
+}
@@ -0,0 +1,268 @@
+use super::Error;
+use super::parse::Source;
+use super::transform::{field, positions};
+use std::{collections::HashMap, fmt};
+
+/// A column whose value differs between the two inputs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    pub column: String,
+    pub old: String,
+    pub new: String,
+}
+
+/// One difference found by `Diff`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// A column only the new input has.
+    ColumnAdded(String),
+    /// A column only the old input has.
+    ColumnRemoved(String),
+    /// A record only the new input has, as column and value pairs.
+    Added { key: Vec<String>, fields: Vec<(String, String)> },
+    /// A record only the old input has.
+    Removed { key: Vec<String>, fields: Vec<(String, String)> },
+    /// A record in both inputs with different values in the columns both
+    /// have.
+    Modified { key: Vec<String>, cells: Vec<Cell> },
+}
+
+impl Change {
+    pub const HEADERS: [&'static str; 5] = ["change", "key", "column", "old", "new"];
+
+    /// The change as records under `HEADERS`, one per cell.
+    pub fn to_records(&self) -> Vec<Vec<String>> {
+        let record = |change: &str, key: &[String], column: &str, old: &str, new: &str| {
+            vec![change.to_string(), key.join(","), column.to_string(), old.to_string(), new.to_string()]
+        };
+        match self {
+            Change::ColumnAdded(column) => vec![record("column added", &[], column, "", "")],
+            Change::ColumnRemoved(column) => vec![record("column removed", &[], column, "", "")],
+            Change::Added { key, fields } => {
+                fields.iter().map(|(column, value)| record("added", key, column, "", value)).collect()
+            }
+            Change::Removed { key, fields } => {
+                fields.iter().map(|(column, value)| record("removed", key, column, value, "")).collect()
+            }
+            Change::Modified { key, cells } => {
+                cells.iter().map(|c| record("modified", key, &c.column, &c.old, &c.new)).collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = |fields: &[(String, String)]| -> String {
+            let fields: Vec<String> = fields.iter().map(|(column, value)| format!("{}={:?}", column, value)).collect();
+            fields.join(", ")
+        };
+        match self {
+            Change::ColumnAdded(column) => write!(f, "+ column `{}`", column),
+            Change::ColumnRemoved(column) => write!(f, "- column `{}`", column),
+            Change::Added { key, fields: added } => write!(f, "+ [{}] {}", key.join(","), fields(added)),
+            Change::Removed { key, fields: removed } => write!(f, "- [{}] {}", key.join(","), fields(removed)),
+            Change::Modified { key, cells } => {
+                let cells: Vec<String> =
+                    cells.iter().map(|c| format!("{}: {:?} -> {:?}", c.column, c.old, c.new)).collect();
+                write!(f, "~ [{}] {}", key.join(","), cells.join(", "))
+            }
+        }
+    }
+}
+
+/// How many records each kind of change touched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DiffSummary {
+    pub added: u64,
+    pub removed: u64,
+    pub modified: u64,
+    pub unchanged: u64,
+}
+
+impl DiffSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.modified == 0
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} modified, {} unchanged",
+            self.added, self.removed, self.modified, self.unchanged
+        )
+    }
+}
+
+/// Compares two inputs record by record, matching records by key columns.
+///
+/// The old input is held in memory and the new one streamed. Column
+/// changes come first, then added and modified records in the order of
+/// the new input, then removed records in the order of the old one.
+/// Columns are matched by name, so reordering them is not a change.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Diff};
+/// let old = "Name,City\nJohn,Tokyo\nFlip,Atlantis\n";
+/// let new = "Name,City\nJohn,Paris\nBrad,Dublin\n";
+/// let mut changes = Vec::new();
+/// let summary = Diff::new(&["Name"])
+///     .diff(parse_records(old), parse_records(new), |c| Ok(changes.push(c.to_string())))
+///     .unwrap();
+/// assert_eq!(changes, [
+///     "~ [John] City: \"Tokyo\" -> \"Paris\"",
+///     "+ [Brad] Name=\"Brad\", City=\"Dublin\"",
+///     "- [Flip] Name=\"Flip\", City=\"Atlantis\"",
+/// ]);
+/// assert_eq!(summary.to_string(), "1 added, 1 removed, 1 modified, 0 unchanged");
+/// ```
+pub struct Diff {
+    key: Vec<String>,
+}
+
+impl Diff {
+    pub fn new<S: AsRef<str>>(key: &[S]) -> Self {
+        Diff { key: key.iter().map(|c| c.as_ref().to_string()).collect() }
+    }
+
+    /// Passes every change to `report`. Keys must be unique in each
+    /// input.
+    pub fn diff<O, N, F>(&self, mut old: O, mut new: N, mut report: F) -> Result<DiffSummary, Error>
+    where
+        O: Source,
+        N: Source,
+        F: FnMut(Change) -> Result<(), Error>,
+    {
+        if self.key.is_empty() {
+            Err("diff needs at least one key column")?
+        }
+        let old_headers = old.headers()?;
+        let new_headers = new.headers()?;
+        let old_keys = positions(&old_headers, &self.key)?;
+        let new_keys = positions(&new_headers, &self.key)?;
+        for column in new_headers.iter().filter(|&c| !old_headers.contains(c)) {
+            report(Change::ColumnAdded(column.clone()))?;
+        }
+        for column in old_headers.iter().filter(|&c| !new_headers.contains(c)) {
+            report(Change::ColumnRemoved(column.clone()))?;
+        }
+        // Columns of both inputs, as positions in the old and the new one.
+        let common: Vec<(usize, usize)> = old_headers
+            .iter()
+            .enumerate()
+            .filter_map(|(i, column)| new_headers.iter().position(|c| c == column).map(|j| (i, j)))
+            .collect();
+
+        let mut records = Vec::new();
+        let mut index: HashMap<Vec<String>, usize> = HashMap::new();
+        while let Some(record) = old.read_record()? {
+            let key = key(&record, &old_keys).map_err(|e| e.at(old.record_position()))?;
+            if index.insert(key.clone(), records.len()).is_some() {
+                return Err(duplicate(&key).at(old.record_position()));
+            }
+            records.push(Some(record));
+        }
+
+        let mut summary = DiffSummary::default();
+        while let Some(record) = new.read_record()? {
+            let key = key(&record, &new_keys).map_err(|e| e.at(new.record_position()))?;
+            let i = match index.get(&key) {
+                Some(&i) => i,
+                None => {
+                    // Marked as seen, to catch the key again.
+                    index.insert(key.clone(), records.len());
+                    records.push(None);
+                    summary.added += 1;
+                    report(Change::Added { fields: pairs(&new_headers, &record), key })?;
+                    continue;
+                }
+            };
+            let previous = match records[i].take() {
+                Some(previous) => previous,
+                None => return Err(duplicate(&key).at(new.record_position())),
+            };
+            let cells: Vec<Cell> = common
+                .iter()
+                .map(|&(i, j)| (i, previous.get(i).cloned().unwrap_or_default(), record.get(j).cloned().unwrap_or_default()))
+                .filter(|(_, old, new)| old != new)
+                .map(|(i, old, new)| Cell { column: old_headers[i].clone(), old, new })
+                .collect();
+            if cells.is_empty() {
+                summary.unchanged += 1;
+            } else {
+                summary.modified += 1;
+                report(Change::Modified { key, cells })?;
+            }
+        }
+        for record in records.into_iter().flatten() {
+            summary.removed += 1;
+            let key = old_keys.iter().map(|&i| record[i].clone()).collect();
+            report(Change::Removed { key, fields: pairs(&old_headers, &record) })?;
+        }
+        Ok(summary)
+    }
+}
+
+fn key(record: &[String], keys: &[usize]) -> Result<Vec<String>, Error> {
+    keys.iter().map(|&i| field(record, i).cloned()).collect()
+}
+
+fn pairs(headers: &[String], record: &[String]) -> Vec<(String, String)> {
+    headers.iter().cloned().zip(record.iter().cloned()).collect()
+}
+
+fn duplicate(key: &[String]) -> Error {
+    Error::Data(format!("duplicate key `{}`", key.join(",")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Change, Diff, DiffSummary};
+    use crate::core::parse::parse_records;
+
+    fn diff(key: &[&str], old: &str, new: &str) -> Result<(Vec<Change>, DiffSummary), crate::err::Error> {
+        let mut changes = Vec::new();
+        let summary = Diff::new(key).diff(parse_records(old), parse_records(new), |change| {
+            changes.push(change);
+            Ok(())
+        })?;
+        Ok((changes, summary))
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = "Id,Kind,Name,Age\n1,a,John,32\n1,b,Amy,20\n2,a,Flip,12\n";
+        let new = "Kind,Id,Age,City\nb,1,21,Paris\na,2,12,Cracow\n";
+        let (changes, summary) = diff(&["Id", "Kind"], old, new).unwrap();
+        assert_eq!(summary, DiffSummary { added: 0, removed: 1, modified: 1, unchanged: 1 });
+        let records: Vec<Vec<String>> = changes.iter().flat_map(Change::to_records).collect();
+        let records: Vec<String> = records.iter().map(|r| r.join("|")).collect();
+        assert_eq!(
+            records,
+            [
+                "column added||City||",
+                "column removed||Name||",
+                "modified|1,b|Age|20|21",
+                "removed|1,a|Id|1|",
+                "removed|1,a|Kind|a|",
+                "removed|1,a|Name|John|",
+                "removed|1,a|Age|32|",
+            ]
+        );
+        assert_eq!(changes[2].to_string(), "~ [1,b] Age: \"20\" -> \"21\"");
+        let (changes, summary) = diff(&["Id"], "Id\n1\n", "Id\n1\n").unwrap();
+        assert!(changes.is_empty() && summary.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_keys() {
+        let e = diff(&["Id"], "Id,A\n1,x\n1,y\n", "Id,A\n1,x\n").unwrap_err();
+        assert_eq!(e.to_string(), "<input>:3: duplicate key `1`\n    1,y");
+        assert!(diff(&["Id"], "Id\n1\n", "Id\n1\n1\n").is_err());
+        assert!(diff(&["Id"], "Id\n", "Id\n2\n2\n").is_err());
+        assert!(diff(&["Code"], "Id\n1\n", "Id\n1\n").is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use super::{Error, Write};
+use super::compress::Finish;
+use encoding_rs::{CoderResult, DecoderResult, Encoding, GB18030, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Cursor, Read},
+    str::{self, FromStr},
+};
+
+/// How much of the input its encoding is sniffed from.
+pub const SNIFF_SIZE: usize = 64 * 1024;
+
+/// A character encoding, and whether the text starts with a byte order
+/// mark.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Charset {
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Charset { encoding: UTF_8, bom: false }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.encoding.name().to_lowercase())?;
+        if self.bom && self.encoding == UTF_8 {
+            f.write_str("-bom")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Charset {
+    type Err = &'static str;
+    /// Takes the WHATWG labels, like `utf-8`, `gbk`, `gb18030`, `utf-16le`
+    /// or `latin1`, and `utf-8-bom`. UTF-16 gets a byte order mark.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        if s == "utf-8-bom" || s == "utf-8-sig" || s == "utf8-bom" {
+            return Ok(Charset { encoding: UTF_8, bom: true });
+        }
+        match Encoding::for_label(s.as_bytes()) {
+            Some(encoding) if encoding == UTF_16LE || encoding == UTF_16BE => Ok(Charset { encoding, bom: true }),
+            Some(encoding) if encoding.output_encoding() == encoding => Ok(Charset { encoding, bom: false }),
+            _ => Err("unknown encoding, try utf-8, utf-8-bom, gbk, gb18030, big5, shift_jis, utf-16le, utf-16be, latin1"),
+        }
+    }
+}
+
+impl Charset {
+    /// Guesses the encoding of `sample`, the start of the input: a byte
+    /// order mark settles it, else UTF-16 shows by its zero bytes, else
+    /// UTF-8 if it is valid, else GB18030 if it is, else Windows-1252.
+    pub fn sniff(sample: &[u8]) -> Charset {
+        if let Some((encoding, _)) = Encoding::for_bom(sample) {
+            return Charset { encoding, bom: true };
+        }
+        let head = &sample[..sample.len().min(1024)];
+        let zeros = |parity: usize| head.iter().skip(parity).step_by(2).filter(|&&b| b == 0).count();
+        let (even, odd) = (zeros(0), zeros(1));
+        let half = head.len() / 2;
+        if half > 0 && odd * 4 > half && even * 4 < odd {
+            return Charset { encoding: UTF_16LE, bom: false };
+        }
+        if half > 0 && even * 4 > half && odd * 4 < even {
+            return Charset { encoding: UTF_16BE, bom: false };
+        }
+        // The sample may end in the middle of a character.
+        let utf8 = match str::from_utf8(sample) {
+            Ok(_) => true,
+            Err(e) => e.error_len().is_none(),
+        };
+        if utf8 {
+            return Charset::default();
+        }
+        let mut decoder = GB18030.new_decoder_without_bom_handling();
+        let mut text = String::with_capacity(sample.len() * 2);
+        match decoder.decode_to_string_without_replacement(sample, &mut text, false) {
+            (DecoderResult::Malformed(..), _) => Charset { encoding: WINDOWS_1252, bom: false },
+            _ => Charset { encoding: GB18030, bom: false },
+        }
+    }
+
+    /// Decodes `input` to UTF-8 from `charset`, or from the one sniffed
+    /// from its first `SNIFF_SIZE` bytes, dropping any byte order mark.
+    /// UTF-8 is passed through for `Reader` to check, or checked here if
+    /// it was sniffed, to tell how to set the encoding should it turn out
+    /// wrong. Other encodings are decoded with replacement characters for
+    /// malformed bytes.
+    pub fn decode<'a, R: BufRead + 'a>(
+        mut input: R,
+        charset: Option<Charset>,
+    ) -> Result<(Box<dyn BufRead + 'a>, Charset), Error> {
+        let mut sample = Vec::with_capacity(SNIFF_SIZE);
+        (&mut input).take(SNIFF_SIZE as u64).read_to_end(&mut sample)?;
+        let bom = Encoding::for_bom(&sample);
+        let sniffed = charset.is_none();
+        let charset = match charset {
+            Some(charset) => Charset { bom: bom.is_some(), ..charset },
+            None => Charset::sniff(&sample),
+        };
+        let mut input = Cursor::new(sample).chain(input);
+        if charset.encoding == UTF_8 {
+            if let Some((_, length)) = bom.filter(|&(encoding, _)| encoding == UTF_8) {
+                input.consume(length);
+            }
+            if sniffed {
+                return Ok((Box::new(Utf8Check::new(input)), charset));
+            }
+            return Ok((Box::new(input), charset));
+        }
+        let decoder = DecodeReaderBytesBuilder::new()
+            .encoding(Some(charset.encoding))
+            .strip_bom(true)
+            .build(input);
+        Ok((Box::new(BufReader::new(decoder)), charset))
+    }
+
+    /// Wraps `output` to encode the UTF-8 written to it in this charset,
+    /// starting with a byte order mark if it has one.
+    pub fn encoder<W: Write>(self, output: W) -> EncodeWriter<W> {
+        EncodeWriter { output, charset: self, started: false, pending: Vec::new(), buffer: Vec::new() }
+    }
+}
+
+/// Input sniffed as UTF-8, failing on invalid bytes past the sample with
+/// an error telling how to set the encoding. Only checked bytes are handed
+/// out, so the error comes on the line they are in.
+struct Utf8Check<R> {
+    input: R,
+    /// How much of the input's buffer is checked.
+    checked: usize,
+    /// A character split between buffers, and how much of it is consumed.
+    split: Vec<u8>,
+    split_consumed: usize,
+}
+
+impl<R: BufRead> Utf8Check<R> {
+    fn new(input: R) -> Self {
+        Utf8Check { input, checked: 0, split: Vec::new(), split_consumed: 0 }
+    }
+
+    /// Reads the character starting at the end of the input's buffer.
+    fn read_split(&mut self) -> io::Result<()> {
+        self.split.clear();
+        self.split_consumed = 0;
+        loop {
+            let byte = match self.input.fill_buf()?.first() {
+                Some(&byte) => byte,
+                None => return Err(not_utf8()),
+            };
+            self.input.consume(1);
+            self.split.push(byte);
+            match str::from_utf8(&self.split) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.error_len().is_none() => {}
+                Err(_) => return Err(not_utf8()),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> BufRead for Utf8Check<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.split_consumed < self.split.len() {
+            return Ok(&self.split[self.split_consumed..]);
+        }
+        let buf = self.input.fill_buf()?;
+        let split = match str::from_utf8(&buf[self.checked..]) {
+            Ok(_) => {
+                self.checked = buf.len();
+                false
+            }
+            Err(e) => {
+                self.checked += e.valid_up_to();
+                match e.error_len() {
+                    _ if self.checked > 0 => false,
+                    None => true,
+                    Some(_) => return Err(not_utf8()),
+                }
+            }
+        };
+        if split {
+            self.read_split()?;
+            return Ok(&self.split);
+        }
+        Ok(&self.input.fill_buf()?[..self.checked])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if self.split_consumed < self.split.len() {
+            self.split_consumed += amount;
+        } else {
+            self.input.consume(amount);
+            self.checked -= amount;
+        }
+    }
+}
+
+impl<R: BufRead> Read for Utf8Check<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+fn not_utf8() -> io::Error {
+    let message = "input is not UTF-8 as its start suggested, set its encoding with --encoding, e.g. --encoding gbk";
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Encodes UTF-8 text into a `Charset` on its way to `output`.
+///
+/// Characters the encoding lacks are written as HTML numeric character
+/// references, as browsers do, rather than failing.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::Charset;
+/// use std::io::Write;
+/// let mut out = Vec::new();
+/// let mut writer = "gbk".parse::<Charset>().unwrap().encoder(&mut out);
+/// writer.write_all("城市\n".as_bytes()).unwrap();
+/// drop(writer);
+/// assert_eq!(out, b"\xb3\xc7\xca\xd0\n");
+/// ```
+pub struct EncodeWriter<W: Write> {
+    output: W,
+    charset: Charset,
+    started: bool,
+    /// The start of a character split between writes.
+    pending: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncodeWriter<W> {
+    fn encode(&mut self, text: &str) {
+        self.buffer.clear();
+        let encoding = self.charset.encoding;
+        if !self.started {
+            self.started = true;
+            if self.charset.bom {
+                self.buffer.extend_from_slice(match encoding {
+                    e if e == UTF_16LE => b"\xff\xfe",
+                    e if e == UTF_16BE => b"\xfe\xff",
+                    _ => b"\xef\xbb\xbf",
+                });
+            }
+        }
+        if encoding == UTF_8 {
+            self.buffer.extend_from_slice(text.as_bytes());
+        } else if encoding == UTF_16LE {
+            self.buffer.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        } else if encoding == UTF_16BE {
+            self.buffer.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            let mut encoder = encoding.new_encoder();
+            let mut text = text;
+            loop {
+                self.buffer.reserve(encoder.max_buffer_length_from_utf8_if_no_unmappables(text.len()).unwrap_or(64));
+                let (result, read, _) = encoder.encode_from_utf8_to_vec(text, &mut self.buffer, true);
+                text = &text[read..];
+                if result == CoderResult::InputEmpty {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for EncodeWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(bytes);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8")),
+        };
+        let pending = std::mem::take(&mut self.pending);
+        self.encode(str::from_utf8(&pending[..valid]).unwrap_or_default());
+        self.pending.extend_from_slice(&pending[valid..]);
+        self.output.write_all(&self.buffer)?;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.encode("");
+            self.output.write_all(&self.buffer)?;
+        }
+        self.output.flush()
+    }
+}
+
+impl<W: Finish> Finish for EncodeWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stream ended inside a UTF-8 character"));
+        }
+        self.flush()?;
+        self.output.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Charset, SNIFF_SIZE};
+    use crate::core::compress::Finish;
+    use crate::core::parse::Reader;
+    use encoding_rs::{GB18030, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+    use std::io::{ErrorKind, Read, Write};
+
+    fn encode(charset: &str, text: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut writer = charset.parse::<Charset>().unwrap().encoder(&mut out);
+        // Byte by byte, to split characters between writes.
+        for byte in text.as_bytes() {
+            writer.write_all(&[*byte]).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        out
+    }
+
+    fn decode(bytes: &[u8], charset: Option<&str>) -> (String, Charset) {
+        let charset = charset.map(|c| c.parse().unwrap());
+        let (mut input, charset) = Charset::decode(bytes, charset).unwrap();
+        let mut text = String::new();
+        input.read_to_string(&mut text).unwrap();
+        (text, charset)
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let text = "姓名,城市\n张三,北京\n";
+        for label in &["utf-8", "utf-8-bom", "gbk", "gb18030", "utf-16le", "utf-16be"] {
+            let bytes = encode(label, text);
+            let (decoded, charset) = decode(&bytes, None);
+            assert_eq!(decoded, text, "{}", label);
+            let expected: Charset = label.parse().unwrap();
+            let encoding = if expected.encoding.name() == "GBK" { GB18030 } else { expected.encoding };
+            assert_eq!(charset, Charset { encoding, ..expected }, "{}", label);
+        }
+        assert_eq!(encode("latin1", "€ ✓"), b"\x80 &#10003;");
+        assert_eq!(encode("utf-8-bom", ""), b"\xef\xbb\xbf");
+
+        // Half of a character is left over when the stream ends.
+        let mut writer = Charset::default().encoder(Vec::new());
+        writer.write_all(&"北".as_bytes()[..2]).unwrap();
+        assert_eq!(writer.finish().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Charset::sniff(b"a,b\n").encoding, UTF_8);
+        // Cut in the middle of a character.
+        assert_eq!(Charset::sniff(&"城市".as_bytes()[..4]).encoding, UTF_8);
+        assert_eq!(Charset::sniff(b"a\0,\0b\0").encoding, UTF_16LE);
+        assert_eq!(Charset::sniff(b"\0a\0,\0b").encoding, UTF_16BE);
+        assert_eq!(Charset::sniff(b"caf\xe9 cr\xe8me,\xff\n").encoding, WINDOWS_1252);
+        assert!("ebcdic".parse::<Charset>().is_err());
+
+        let bytes = b"\xef\xbb\xbfCity,Age\nTokyo,32\n";
+        let (input, charset) = Charset::decode(&bytes[..], None).unwrap();
+        assert!(charset.bom);
+        assert_eq!(Reader::new(input).headers().unwrap(), vec!["City", "Age"]);
+        let (text, _) = decode(b"\xb3\xc7\xca\xd0", Some("gbk"));
+        assert_eq!(text, "城市");
+    }
+
+    #[test]
+    fn test_sniff_past_the_first_block() {
+        let mut bytes = "Id,City\n".repeat(2000).into_bytes();
+        bytes.extend_from_slice(b"1,\xb1\xb1\xbe\xa9\n");
+        let (text, charset) = decode(&bytes, None);
+        assert_eq!((charset.encoding, text.ends_with("1,北京\n")), (GB18030, true));
+
+        // Characters split where the sample ends.
+        let text = "Id,北京\n".repeat(SNIFF_SIZE / 4);
+        assert_eq!(decode(text.as_bytes(), None).0, text);
+
+        // Past the sample, invalid UTF-8 fails on its line, telling how to
+        // set the encoding.
+        let mut bytes = "Id,City\n".repeat(SNIFF_SIZE / 8 + 1).into_bytes();
+        bytes.extend_from_slice(b"1,\xb1\xb1\xbe\xa9\n");
+        let (input, _) = Charset::decode(&bytes[..], None).unwrap();
+        let error = Reader::new(input).try_for_each(|r| r.map(|_| ())).unwrap_err();
+        assert!(error.to_string().contains(&format!(":{}: ", SNIFF_SIZE / 8 + 2)), "{}", error);
+        assert!(error.to_string().contains("--encoding gbk"), "{}", error);
+    }
+}
@@ -0,0 +1,641 @@
+use super::Error;
+use super::stats::format_number;
+use super::transform::{field, position, Transform};
+use std::{cmp::Ordering, fmt};
+
+/// What an expression evaluates to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// An empty cell, or a function of one.
+    Null,
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    /// A cell as read, untyped: a number where it parses as one and a
+    /// number is wanted, text otherwise.
+    Cell(String),
+}
+
+impl Value {
+    fn cell(value: &str) -> Value {
+        if value.is_empty() { Value::Null } else { Value::Cell(value.to_string()) }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            Value::Cell(text) => text.trim().parse::<f64>().ok().filter(|n| n.is_finite()),
+            _ => None,
+        }
+    }
+
+    fn expect_number(&self) -> Result<f64, Error> {
+        self.number().ok_or_else(|| Error::Data(format!("`{}` is not a number", self)))
+    }
+
+    fn truth(&self) -> Result<bool, Error> {
+        match self {
+            Value::Bool(b) => Ok(*b),
+            Value::Null => Ok(false),
+            Value::Cell(text) if text == "true" || text == "false" => Ok(text == "true"),
+            value => Err(Error::Data(format!("`{}` is not a boolean", value))),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(number) => f.write_str(&format_number(*number)),
+            Value::Text(text) | Value::Cell(text) => f.write_str(text),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Upper,
+    Lower,
+    Trim,
+    Len,
+    Substr,
+    Coalesce,
+    If,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name.to_lowercase().as_str() {
+            "upper" => Some(Function::Upper),
+            "lower" => Some(Function::Lower),
+            "trim" => Some(Function::Trim),
+            "len" => Some(Function::Len),
+            "substr" => Some(Function::Substr),
+            "coalesce" => Some(Function::Coalesce),
+            "if" => Some(Function::If),
+            _ => None,
+        }
+    }
+
+    /// The least and most arguments taken.
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Function::Upper | Function::Lower | Function::Trim | Function::Len => (1, 1),
+            Function::Substr => (2, 3),
+            Function::Coalesce => (1, usize::MAX),
+            Function::If => (3, 3),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Literal(Value),
+    Column(String, usize),
+    Neg(Box<Node>),
+    Not(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Name(String),
+    /// A column name in braces, for names that aren't identifiers.
+    Column(String),
+    Symbol(&'static str),
+}
+
+// Two-character symbols first, so `<=` is not read as `<`.
+const SYMBOLS: [&str; 15] = ["==", "!=", "<>", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "%", "(", ")"];
+
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = if c.is_ascii_digit() || c == '.' {
+            let mut number = String::new();
+            while let Some(&(_, c)) = chars.peek().filter(|&&(_, c)| c.is_ascii_digit() || c == '.') {
+                number.push(c);
+                chars.next();
+            }
+            match number.parse() {
+                Ok(number) => Token::Number(number),
+                Err(_) => return Err((start, format!("invalid number `{}`", number))),
+            }
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    // A doubled quote stands for itself.
+                    Some((_, q)) if q == c && chars.peek().map(|&(_, n)| n) == Some(c) => {
+                        chars.next();
+                        value.push(c);
+                    }
+                    Some((_, q)) if q == c => break,
+                    Some((_, q)) => value.push(q),
+                    None => return Err((start, "unclosed string".to_string())),
+                }
+            }
+            Token::Text(value)
+        } else if c == '{' {
+            chars.next();
+            let mut column = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '}')) => break,
+                    Some((_, c)) => column.push(c),
+                    None => return Err((start, "unclosed `{`".to_string())),
+                }
+            }
+            Token::Column(column)
+        } else if c.is_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&(_, c)) = chars.peek().filter(|&&(_, c)| c.is_alphanumeric() || c == '_') {
+                name.push(c);
+                chars.next();
+            }
+            Token::Name(name)
+        } else if c == ',' {
+            chars.next();
+            Token::Symbol(",")
+        } else {
+            let rest = &text[start..];
+            match SYMBOLS.iter().find(|s| rest.starts_with(*s)) {
+                Some(symbol) => {
+                    for _ in 0..symbol.len() {
+                        chars.next();
+                    }
+                    Token::Symbol(symbol)
+                }
+                None => return Err((start, format!("unexpected `{}`", c))),
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+/// A recursive descent parser, one method per precedence level.
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+}
+
+type Parsed = Result<Node, (usize, String)>;
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.next).map_or(self.end, |&(offset, _)| offset)
+    }
+
+    fn eat_symbol(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.next += 1;
+            return true;
+        }
+        false
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Name(name)) if name.eq_ignore_ascii_case(keyword) => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), (usize, String)> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err((self.offset(), format!("expected `{}`", symbol)))
+        }
+    }
+
+    fn or(&mut self) -> Parsed {
+        let mut left = self.and()?;
+        while self.eat_keyword("or") {
+            left = Node::Binary(BinaryOp::Or, Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Parsed {
+        let mut left = self.not()?;
+        while self.eat_keyword("and") {
+            left = Node::Binary(BinaryOp::And, Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Parsed {
+        if self.eat_keyword("not") {
+            return Ok(Node::Not(Box::new(self.not()?)));
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Parsed {
+        let left = self.sum()?;
+        let op = match self.peek() {
+            Some(Token::Symbol("=")) | Some(Token::Symbol("==")) => BinaryOp::Eq,
+            Some(Token::Symbol("!=")) | Some(Token::Symbol("<>")) => BinaryOp::Ne,
+            Some(Token::Symbol("<")) => BinaryOp::Lt,
+            Some(Token::Symbol("<=")) => BinaryOp::Le,
+            Some(Token::Symbol(">")) => BinaryOp::Gt,
+            Some(Token::Symbol(">=")) => BinaryOp::Ge,
+            _ => return Ok(left),
+        };
+        self.next += 1;
+        Ok(Node::Binary(op, Box::new(left), Box::new(self.sum()?)))
+    }
+
+    fn sum(&mut self) -> Parsed {
+        let mut left = self.product()?;
+        loop {
+            let op = if self.eat_symbol("+") {
+                BinaryOp::Add
+            } else if self.eat_symbol("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Node::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Parsed {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat_symbol("*") {
+                BinaryOp::Mul
+            } else if self.eat_symbol("/") {
+                BinaryOp::Div
+            } else if self.eat_symbol("%") {
+                BinaryOp::Rem
+            } else {
+                return Ok(left);
+            };
+            left = Node::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Parsed {
+        if self.eat_symbol("-") {
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Parsed {
+        let offset = self.offset();
+        let token = match self.tokens.get(self.next) {
+            Some((_, token)) => token.clone(),
+            None => return Err((offset, "expected a value".to_string())),
+        };
+        self.next += 1;
+        match token {
+            Token::Number(number) => Ok(Node::Literal(Value::Number(number))),
+            Token::Text(text) => Ok(Node::Literal(Value::Text(text))),
+            Token::Column(column) => Ok(Node::Column(column, 0)),
+            Token::Symbol("(") => {
+                let node = self.or()?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Name(name) if self.eat_symbol("(") => {
+                let function = match Function::from_name(&name) {
+                    Some(function) => function,
+                    None => return Err((offset, format!("unknown function `{}`", name))),
+                };
+                let mut args = Vec::new();
+                if !self.eat_symbol(")") {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat_symbol(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                let (min, max) = function.arity();
+                if args.len() < min || args.len() > max {
+                    return Err((offset, format!("wrong number of arguments to `{}`", name)));
+                }
+                Ok(Node::Call(function, args))
+            }
+            Token::Name(name) => match name.to_lowercase().as_str() {
+                "true" => Ok(Node::Literal(Value::Bool(true))),
+                "false" => Ok(Node::Literal(Value::Bool(false))),
+                "null" => Ok(Node::Literal(Value::Null)),
+                _ => Ok(Node::Column(name, 0)),
+            },
+            Token::Symbol(symbol) => Err((offset, format!("unexpected `{}`", symbol))),
+        }
+    }
+}
+
+/// An expression over the columns of a record, parsed once and evaluated
+/// per record.
+///
+/// Columns are named by identifiers, or in braces like `{First Name}` or
+/// `{#2}`. Strings are quoted with `'` or `"`. By precedence, lowest
+/// first, there are `or`, `and`, `not`, the comparisons `=`, `!=`, `<`,
+/// `<=`, `>`, `>=`, then `+` and `-`, then `*`, `/` and `%`, then unary
+/// `-`. `+` adds numbers and concatenates anything else; cells count as
+/// numbers when they parse as one. Empty cells are null, which every
+/// operator and function but `coalesce` and `if` passes on.
+///
+/// The functions are `upper(s)`, `lower(s)`, `trim(s)`, `len(s)`,
+/// `substr(s, start[, length])` counting characters from zero,
+/// `coalesce(a, b, ...)` giving the first value that isn't null, and
+/// `if(condition, then, else)`.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::Expr;
+/// let mut expr = Expr::parse("if(qty > 2, price * qty * 0.9, price * qty)").unwrap();
+/// expr.resolve(&["price".to_string(), "qty".to_string()]).unwrap();
+/// let value = expr.eval(&["2.5".to_string(), "4".to_string()]).unwrap();
+/// assert_eq!(value.to_string(), "9");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Expr {
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, Error> {
+        let invalid = |(offset, message): (usize, String)| {
+            let at = text[..offset].chars().count() + 1;
+            Error::Data(format!("invalid expression `{}`: {} at character {}", text, message, at))
+        };
+        let tokens = tokenize(text).map_err(invalid)?;
+        let mut parser = Parser { tokens, next: 0, end: text.len() };
+        let root = parser.or().map_err(invalid)?;
+        if parser.next < parser.tokens.len() {
+            return Err(invalid((parser.offset(), "unexpected input".to_string())));
+        }
+        Ok(Expr { root })
+    }
+
+    /// Resolves the column names to positions in `headers`.
+    pub fn resolve(&mut self, headers: &[String]) -> Result<(), Error> {
+        fn visit(node: &mut Node, headers: &[String]) -> Result<(), Error> {
+            match node {
+                Node::Literal(_) => Ok(()),
+                Node::Column(column, index) => {
+                    *index = position(headers, column)?;
+                    Ok(())
+                }
+                Node::Neg(node) | Node::Not(node) => visit(node, headers),
+                Node::Binary(_, left, right) => {
+                    visit(left, headers)?;
+                    visit(right, headers)
+                }
+                Node::Call(_, args) => args.iter_mut().try_for_each(|arg| visit(arg, headers)),
+            }
+        }
+        visit(&mut self.root, headers)
+    }
+
+    pub fn eval(&self, record: &[String]) -> Result<Value, Error> {
+        eval(&self.root, record)
+    }
+}
+
+fn eval(node: &Node, record: &[String]) -> Result<Value, Error> {
+    match node {
+        Node::Literal(value) => Ok(value.clone()),
+        Node::Column(_, index) => Ok(Value::cell(field(record, *index)?)),
+        Node::Neg(node) => match eval(node, record)? {
+            Value::Null => Ok(Value::Null),
+            value => Ok(Value::Number(-value.expect_number()?)),
+        },
+        Node::Not(node) => Ok(Value::Bool(!eval(node, record)?.truth()?)),
+        Node::Binary(BinaryOp::And, left, right) => {
+            Ok(Value::Bool(eval(left, record)?.truth()? && eval(right, record)?.truth()?))
+        }
+        Node::Binary(BinaryOp::Or, left, right) => {
+            Ok(Value::Bool(eval(left, record)?.truth()? || eval(right, record)?.truth()?))
+        }
+        Node::Binary(op, left, right) => binary(*op, eval(left, record)?, eval(right, record)?),
+        Node::Call(Function::If, args) => {
+            let branch = if eval(&args[0], record)?.truth()? { &args[1] } else { &args[2] };
+            eval(branch, record)
+        }
+        Node::Call(Function::Coalesce, args) => {
+            for arg in args {
+                match eval(arg, record)? {
+                    Value::Null => {}
+                    value => return Ok(value),
+                }
+            }
+            Ok(Value::Null)
+        }
+        Node::Call(function, args) => {
+            let args = args.iter().map(|arg| eval(arg, record)).collect::<Result<Vec<_>, _>>()?;
+            call(*function, &args)
+        }
+    }
+}
+
+fn binary(op: BinaryOp, left: Value, right: Value) -> Result<Value, Error> {
+    let numbers = (left.number(), right.number());
+    if op == BinaryOp::Eq || op == BinaryOp::Ne {
+        let equal = match numbers {
+            (Some(a), Some(b)) => a == b,
+            _ => left.to_string() == right.to_string(),
+        };
+        return Ok(Value::Bool(equal == (op == BinaryOp::Eq)));
+    }
+    if left == Value::Null || right == Value::Null {
+        return Ok(Value::Null);
+    }
+    let ordering = || match numbers {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => left.to_string().cmp(&right.to_string()),
+    };
+    let arithmetic = |f: fn(f64, f64) -> f64| -> Result<Value, Error> {
+        Ok(Value::Number(f(left.expect_number()?, right.expect_number()?)))
+    };
+    match op {
+        BinaryOp::Add => match numbers {
+            (Some(a), Some(b)) => Ok(Value::Number(a + b)),
+            _ => Ok(Value::Text(format!("{}{}", left, right))),
+        },
+        BinaryOp::Sub => arithmetic(|a, b| a - b),
+        BinaryOp::Mul => arithmetic(|a, b| a * b),
+        BinaryOp::Div | BinaryOp::Rem if right.expect_number()? == 0.0 => Err(Error::Data("division by zero".to_string())),
+        BinaryOp::Div => arithmetic(|a, b| a / b),
+        BinaryOp::Rem => arithmetic(|a, b| a % b),
+        BinaryOp::Lt => Ok(Value::Bool(ordering() == Ordering::Less)),
+        BinaryOp::Le => Ok(Value::Bool(ordering() != Ordering::Greater)),
+        BinaryOp::Gt => Ok(Value::Bool(ordering() == Ordering::Greater)),
+        BinaryOp::Ge => Ok(Value::Bool(ordering() != Ordering::Less)),
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::And | BinaryOp::Or => unreachable!("handled above"),
+    }
+}
+
+fn call(function: Function, args: &[Value]) -> Result<Value, Error> {
+    if args.first() == Some(&Value::Null) {
+        return Ok(Value::Null);
+    }
+    let text = args[0].to_string();
+    match function {
+        Function::Upper => Ok(Value::Text(text.to_uppercase())),
+        Function::Lower => Ok(Value::Text(text.to_lowercase())),
+        Function::Trim => Ok(Value::Text(text.trim().to_string())),
+        Function::Len => Ok(Value::Number(text.chars().count() as f64)),
+        Function::Substr => {
+            let count = |value: &Value| match value.expect_number()? {
+                n if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+                n => Err(Error::Data(format!("substr needs a whole number, found `{}`", format_number(n)))),
+            };
+            let start = count(&args[1])?;
+            let length = match args.get(2) {
+                Some(length) => count(length)?,
+                None => usize::MAX,
+            };
+            Ok(Value::Text(text.chars().skip(start).take(length).collect()))
+        }
+        Function::Coalesce | Function::If => unreachable!("evaluated lazily"),
+    }
+}
+
+/// Sets a column to an expression of the record, adding the column at
+/// the end unless the header already has it.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{Compute, Transform};
+/// let mut compute = Compute::new("name", "upper(first) + ' ' + last").unwrap();
+/// let headers = vec!["first".to_string(), "last".to_string()];
+/// assert_eq!(compute.headers(&headers).unwrap(), vec!["first", "last", "name"]);
+/// let record = vec!["John".to_string(), "Doe".to_string()];
+/// assert_eq!(compute.apply(record).unwrap().unwrap(), vec!["John", "Doe", "JOHN Doe"]);
+/// ```
+pub struct Compute {
+    column: String,
+    expr: Expr,
+    /// The position of an existing column, else the width of the header.
+    index: Option<usize>,
+    width: usize,
+}
+
+impl Compute {
+    pub fn new(column: &str, expr: &str) -> Result<Compute, Error> {
+        Ok(Compute { column: column.to_string(), expr: Expr::parse(expr)?, index: None, width: 0 })
+    }
+}
+
+impl Transform for Compute {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        self.expr.resolve(headers)?;
+        self.index = headers.iter().position(|h| h == &self.column);
+        self.width = headers.len();
+        let mut headers = headers.to_vec();
+        if self.index.is_none() {
+            headers.push(self.column.clone());
+        }
+        Ok(headers)
+    }
+
+    fn apply(&self, mut record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        let value = self.expr.eval(&record)?.to_string();
+        match self.index {
+            Some(index) => record[index] = value,
+            None => {
+                record.resize(self.width, String::new());
+                record.push(value);
+            }
+        }
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Expr;
+
+    fn eval(expr: &str, record: &[&str]) -> String {
+        let mut expr = Expr::parse(expr).unwrap();
+        let headers: Vec<String> = ["price", "qty", "first", "last", "First Name"].iter().map(|h| h.to_string()).collect();
+        expr.resolve(&headers).unwrap();
+        let record: Vec<String> = record.iter().map(|v| v.to_string()).collect();
+        match expr.eval(&record) {
+            Ok(value) => value.to_string(),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_eval() {
+        let row = ["2.5", "4", " ann ", "", "Jo"];
+        assert_eq!(eval("price * qty", &row), "10");
+        assert_eq!(eval("1 + 2 * 3 - -1", &row), "8");
+        assert_eq!(eval("(1 + 2) * 3 % 4", &row), "1");
+        assert_eq!(eval("qty / 3", &row), "1.3333");
+        assert_eq!(eval("upper(trim(first)) + '-' + {First Name}", &row), "ANN-Jo");
+        assert_eq!(eval("len(first) + substr('abcdef', 2, 3)", &row), "5cde");
+        assert_eq!(eval("trim(qty) + price", &row), "42.5");
+        assert_eq!(eval("'it''s' + \" ok\"", &row), "it's ok");
+        assert_eq!(eval("first + last", &row), "");
+        assert_eq!(eval("coalesce(last, {#4}, 'none')", &row), "Jo");
+        assert_eq!(eval("if(qty >= 4 and not price > 3, 'bulk', 'single')", &row), "bulk");
+        assert_eq!(eval("if(last, 1, 2)", &row), "2");
+        assert_eq!(eval("last = null or qty == '4.0'", &row), "true");
+        assert_eq!(eval("'b' < 'a' or 10 < 9", &row), "false");
+        assert_eq!(eval("price / (qty - 4)", &row), "error: division by zero");
+        assert_eq!(eval("first * 2", &row), "error: ` ann ` is not a number");
+        assert_eq!(eval("substr(first, -1)", &row), "error: substr needs a whole number, found `-1`");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |expr: &str| Expr::parse(expr).err().map(|e| e.to_string()).unwrap_or_default();
+        assert_eq!(error("price *"), "invalid expression `price *`: expected a value at character 8");
+        assert!(error("upper(first, last)").contains("wrong number of arguments to `upper` at character 1"));
+        assert!(error("shout(first)").contains("unknown function `shout`"));
+        assert!(error("(1 + 2").contains("expected `)`"));
+        assert!(error("'open").contains("unclosed string"));
+        assert!(error("1 2").contains("unexpected input at character 3"));
+        assert!(error("a ? b").contains("unexpected `?`"));
+        let mut expr = Expr::parse("missing + 1").unwrap();
+        assert!(expr.resolve(&["price".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+use super::Error;
+use super::transform::{field, position, Pipeline, Transform};
+use regex::Regex;
+
+/// How a `Filter` compares a cell with its value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+    Empty,
+    NotEmpty,
+}
+
+// Two-character operators first, so `<=` is not read as `<`.
+const OPERATORS: [(&str, Op); 8] = [
+    ("!=", Op::Ne),
+    ("<=", Op::Le),
+    (">=", Op::Ge),
+    ("!~", Op::NotMatch),
+    ("=", Op::Eq),
+    ("<", Op::Lt),
+    (">", Op::Gt),
+    ("~", Op::Match),
+];
+
+/// A predicate over one column of a record.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::Filter;
+/// let mut filter = Filter::parse("Age>=30").unwrap();
+/// let headers = vec!["Name".to_string(), "Age".to_string()];
+/// filter.resolve(&headers).unwrap();
+/// let record = vec!["John".to_string(), "32".to_string()];
+/// assert!(filter.matches(&record).unwrap());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Filter {
+    column: String,
+    op: Op,
+    value: String,
+    number: f64,
+    regex: Option<Regex>,
+    index: usize,
+}
+
+impl Filter {
+    pub fn new(column: &str, op: Op, value: &str) -> Result<Filter, Error> {
+        let mut number = 0.0;
+        let mut regex = None;
+        match op {
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                number = match value.trim().parse() {
+                    Ok(number) => number,
+                    Err(_) => Err("numeric comparison needs a number")?,
+                };
+            }
+            Op::Match | Op::NotMatch => regex = Some(Regex::new(value)?),
+            _ => {}
+        }
+        Ok(Filter {
+            column: column.to_string(),
+            op,
+            value: value.to_string(),
+            number,
+            regex,
+            index: 0,
+        })
+    }
+
+    /// Parses `COLUMN<op>VALUE`, where `<op>` is one of `=`, `!=`, `<`,
+    /// `<=`, `>`, `>=`, `~` (regex match) or `!~` (no match), or one of
+    /// `COLUMN:empty` and `COLUMN:nonempty`.
+    pub fn parse(expr: &str) -> Result<Filter, Error> {
+        if let Some(column) = expr.strip_suffix(":empty") {
+            return Filter::new(column, Op::Empty, "");
+        }
+        if let Some(column) = expr.strip_suffix(":nonempty") {
+            return Filter::new(column, Op::NotEmpty, "");
+        }
+        for (i, _) in expr.char_indices() {
+            let rest = &expr[i..];
+            if let Some(&(symbol, op)) = OPERATORS.iter().find(|(s, _)| rest.starts_with(s)) {
+                return Filter::new(&expr[..i], op, &rest[symbol.len()..]);
+            }
+        }
+        Err("filter needs an operator, e.g. City=Tokyo or Age>30")?
+    }
+
+    /// Looks the filtered column up in `headers`.
+    pub fn resolve(&mut self, headers: &[String]) -> Result<(), Error> {
+        self.index = position(headers, &self.column)?;
+        Ok(())
+    }
+
+    pub fn matches(&self, record: &[String]) -> Result<bool, Error> {
+        let cell = field(record, self.index)?;
+        let matched = match self.op {
+            Op::Eq => *cell == self.value,
+            Op::Ne => *cell != self.value,
+            Op::Empty => cell.trim().is_empty(),
+            Op::NotEmpty => !cell.trim().is_empty(),
+            Op::Match | Op::NotMatch => {
+                let found = self.regex.as_ref().is_some_and(|re| re.is_match(cell));
+                found == (self.op == Op::Match)
+            }
+            // A cell that is not a number never satisfies a comparison.
+            Op::Lt | Op::Le | Op::Gt | Op::Ge => match cell.trim().parse::<f64>() {
+                Ok(n) => match self.op {
+                    Op::Lt => n < self.number,
+                    Op::Le => n <= self.number,
+                    Op::Gt => n > self.number,
+                    _ => n >= self.number,
+                },
+                Err(_) => false,
+            },
+        };
+        Ok(matched)
+    }
+}
+
+fn resolve_all(filters: &mut [Filter], headers: &[String]) -> Result<(), Error> {
+    for filter in filters.iter_mut() {
+        filter.resolve(headers)?;
+    }
+    Ok(())
+}
+
+fn match_all(filters: &[Filter], record: &[String]) -> Result<bool, Error> {
+    for filter in filters {
+        if !filter.matches(record)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Keeps only the records matching every filter.
+pub struct Where {
+    filters: Vec<Filter>,
+}
+
+impl Where {
+    pub fn new(filters: Vec<Filter>) -> Self {
+        Where { filters }
+    }
+}
+
+impl Transform for Where {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        resolve_all(&mut self.filters, headers)?;
+        Ok(headers.to_vec())
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        if match_all(&self.filters, &record)? {
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Applies `steps` to the records matching every filter and passes the
+/// others through untouched, so `steps` must not change the header.
+pub struct When {
+    filters: Vec<Filter>,
+    steps: Pipeline,
+}
+
+impl When {
+    pub fn new(filters: Vec<Filter>, steps: Pipeline) -> Self {
+        When { filters, steps }
+    }
+}
+
+impl Transform for When {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        resolve_all(&mut self.filters, headers)?;
+        if self.steps.headers(headers)? != headers {
+            Err("conditional steps must not change the header")?
+        }
+        Ok(headers.to_vec())
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        if match_all(&self.filters, &record)? {
+            self.steps.apply(record)
+        } else {
+            Ok(Some(record))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Op, When, Where};
+    use crate::core::transform::{Pipeline, Rename, Set, Transform};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn check(expr: &str, record: &[&str]) -> bool {
+        let mut filter = Filter::parse(expr).unwrap();
+        filter.resolve(&strings(&["Name", "Age", "Note"])).unwrap();
+        filter.matches(&strings(record)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_operators() {
+        let row = ["John", "32", ""];
+        assert!(check("Name=John", &row));
+        assert!(!check("Name!=John", &row));
+        assert!(check("Age>30", &row) && check("Age>=32", &row) && check("Age<=32", &row));
+        assert!(!check("Age<32", &row));
+        assert!(check("Name~^J.h", &row) && check("Name!~^D", &row));
+        assert!(check("Note:empty", &row) && !check("Note:nonempty", &row));
+        assert!(!check("Name>1", &row));
+        assert_eq!(Filter::parse("a<=1").unwrap().op, Op::Le);
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert!(Filter::parse("Age").is_err());
+        assert!(Filter::parse("Age>old").is_err());
+        assert!(Filter::parse("Name~(").is_err());
+        let mut filter = Filter::parse("Missing=1").unwrap();
+        assert!(filter.resolve(&strings(&["Name"])).is_err());
+    }
+
+    #[test]
+    fn test_where_and_when() {
+        let headers = strings(&["Name", "Age", "Note"]);
+        let mut only_old = Where::new(vec![Filter::parse("Age>40").unwrap()]);
+        only_old.headers(&headers).unwrap();
+        assert_eq!(only_old.apply(strings(&["John", "32", ""])).unwrap(), None);
+
+        let steps = Pipeline::new().then(Set::new("Note", "old"));
+        let mut mark_old = When::new(vec![Filter::parse("Age>40").unwrap()], steps);
+        mark_old.headers(&headers).unwrap();
+        let young = mark_old.apply(strings(&["John", "32", ""])).unwrap();
+        let old = mark_old.apply(strings(&["Brad", "42", ""])).unwrap();
+        assert_eq!(young, Some(strings(&["John", "32", ""])));
+        assert_eq!(old, Some(strings(&["Brad", "42", "old"])));
+
+        let steps = Pipeline::new().then(Rename::new("Note", "Memo"));
+        let mut rename = When::new(Vec::new(), steps);
+        assert!(rename.headers(&headers).is_err());
+    }
+}
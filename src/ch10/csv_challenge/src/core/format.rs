@@ -0,0 +1,270 @@
+use super::{Error, Write};
+use super::dialect::Dialect;
+use super::parse::Writer;
+use super::compress::{Compression, Finish};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::{io::BufWriter, path::Path, str::FromStr};
+
+/// Where a record stream ends up, in one of the output `Format`s.
+pub trait Output {
+    /// Called once before any record. `synthetic` headers were made up
+    /// for an input without header row, so formats that don't need keys
+    /// leave them out.
+    fn write_headers(&mut self, headers: &[String], synthetic: bool) -> Result<(), Error>;
+    fn write_record(&mut self, record: &[String]) -> Result<(), Error>;
+    /// Closes the document and flushes the output.
+    fn finish(&mut self) -> Result<(), Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// CSV in the dialect of the input.
+    Csv,
+    Tsv,
+    /// A JSON array of objects keyed by header.
+    Json,
+    /// One JSON object per line.
+    Ndjson,
+    /// A GitHub-style Markdown table.
+    Markdown,
+}
+
+impl Format {
+    /// Wraps `output` in a writer for this format, CSV being written in
+    /// `dialect`.
+    pub fn writer<'a, W: Finish + 'a>(self, output: W, dialect: Dialect) -> Box<dyn Output + 'a> {
+        match self {
+            Format::Csv => Box::new(Writer::new(output).with_dialect(dialect)),
+            Format::Tsv => {
+                let dialect = Dialect { terminator: dialect.terminator, ..Dialect::tsv() };
+                Box::new(Writer::new(output).with_dialect(dialect))
+            }
+            Format::Json => Box::new(JsonWriter::new(output)),
+            Format::Ndjson => Box::new(JsonWriter::lines(output)),
+            Format::Markdown => Box::new(MarkdownWriter::new(output)),
+        }
+    }
+}
+
+impl Format {
+    /// The format a file name's extension stands for, if any, looking
+    /// past a compression extension as in `.csv.gz`.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let mut path = Path::new(path);
+        if Compression::from_path(path.to_str()?) != Compression::None {
+            path = Path::new(path.file_stem()?);
+        }
+        let extension = path.extension()?.to_str()?;
+        extension.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "json" => Ok(Format::Json),
+            "ndjson" | "jsonl" => Ok(Format::Ndjson),
+            "markdown" | "md" => Ok(Format::Markdown),
+            _ => Err("format must be one of csv, tsv, json, ndjson, markdown"),
+        }
+    }
+}
+
+impl<W: Finish> Output for Writer<W> {
+    fn write_headers(&mut self, headers: &[String], synthetic: bool) -> Result<(), Error> {
+        if synthetic {
+            return Ok(());
+        }
+        Writer::write_record(self, headers)
+    }
+
+    fn write_record(&mut self, record: &[String]) -> Result<(), Error> {
+        Writer::write_record(self, record)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        Writer::finish(self)
+    }
+}
+
+/// A record as a JSON object, keeping the column order.
+struct Object<'a> {
+    headers: &'a [String],
+    record: &'a [String],
+}
+
+impl Serialize for Object<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.headers.len()))?;
+        for (header, field) in self.headers.iter().zip(self.record) {
+            map.serialize_entry(header, field)?;
+        }
+        map.end()
+    }
+}
+
+/// Writes records as JSON objects keyed by header, either as one array
+/// or one object per line.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{JsonWriter, Output};
+/// let mut out = Vec::new();
+/// let mut writer = JsonWriter::new(&mut out);
+/// writer.write_headers(&["City".to_string()], false).unwrap();
+/// writer.write_record(&["Tokyo".to_string()]).unwrap();
+/// writer.finish().unwrap();
+/// drop(writer);
+/// assert_eq!(String::from_utf8(out).unwrap(), "[\n  {\"City\":\"Tokyo\"}\n]\n");
+/// ```
+pub struct JsonWriter<W: Write> {
+    output: BufWriter<W>,
+    headers: Vec<String>,
+    lines: bool,
+    records: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(output: W) -> Self {
+        JsonWriter { output: BufWriter::new(output), headers: Vec::new(), lines: false, records: 0 }
+    }
+
+    /// Newline-delimited JSON.
+    pub fn lines(output: W) -> Self {
+        JsonWriter { lines: true, ..JsonWriter::new(output) }
+    }
+}
+
+impl<W: Finish> Output for JsonWriter<W> {
+    fn write_headers(&mut self, headers: &[String], _synthetic: bool) -> Result<(), Error> {
+        self.headers = headers.to_vec();
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[String]) -> Result<(), Error> {
+        if !self.lines {
+            let separator: &[u8] = if self.records == 0 { b"[\n  " } else { b",\n  " };
+            self.output.write_all(separator)?;
+        }
+        let object = Object { headers: &self.headers, record };
+        serde_json::to_writer(&mut self.output, &object).map_err(std::io::Error::from)?;
+        if self.lines {
+            self.output.write_all(b"\n")?;
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        if !self.lines {
+            let end: &[u8] = if self.records == 0 { b"[]\n" } else { b"\n]\n" };
+            self.output.write_all(end)?;
+        }
+        self.output.flush()?;
+        self.output.get_mut().finish()?;
+        Ok(())
+    }
+}
+
+/// Writes records as a GitHub-style Markdown table. Pipes are escaped
+/// and line breaks become `<br>`, so every record stays on one row.
+pub struct MarkdownWriter<W: Write> {
+    output: BufWriter<W>,
+    buffer: String,
+}
+
+impl<W: Write> MarkdownWriter<W> {
+    pub fn new(output: W) -> Self {
+        MarkdownWriter { output: BufWriter::new(output), buffer: String::new() }
+    }
+
+    fn write_row<S: AsRef<str>>(&mut self, cells: &[S]) -> Result<(), Error> {
+        self.buffer.clear();
+        self.buffer.push('|');
+        for cell in cells {
+            self.buffer.push(' ');
+            let cell = cell.as_ref().replace("\r\n", "<br>");
+            for c in cell.chars() {
+                match c {
+                    '|' => self.buffer.push_str("\\|"),
+                    '\r' | '\n' => self.buffer.push_str("<br>"),
+                    c => self.buffer.push(c),
+                }
+            }
+            self.buffer.push_str(" |");
+        }
+        self.buffer.push('\n');
+        self.output.write_all(self.buffer.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Finish> Output for MarkdownWriter<W> {
+    fn write_headers(&mut self, headers: &[String], _synthetic: bool) -> Result<(), Error> {
+        self.write_row(headers)?;
+        self.write_row(&vec!["---"; headers.len()])
+    }
+
+    fn write_record(&mut self, record: &[String]) -> Result<(), Error> {
+        self.write_row(record)
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.output.flush()?;
+        self.output.get_mut().finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+    use crate::core::dialect::Dialect;
+
+    fn render(format: Format, synthetic: bool, records: &[&[&str]]) -> String {
+        let mut out = Vec::new();
+        {
+            let mut writer = format.writer(&mut out, Dialect::default());
+            let headers = vec!["City".to_string(), "Note".to_string()];
+            writer.write_headers(&headers, synthetic).unwrap();
+            for record in records {
+                let record: Vec<String> = record.iter().map(|f| f.to_string()).collect();
+                writer.write_record(&record).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_formats() {
+        let records: &[&[&str]] = &[&["Tokyo", "say \"hi\""], &["Paris", "a|b\nc"]];
+        assert_eq!(
+            render(Format::Json, false, records),
+            "[\n  {\"City\":\"Tokyo\",\"Note\":\"say \\\"hi\\\"\"},\n  {\"City\":\"Paris\",\"Note\":\"a|b\\nc\"}\n]\n"
+        );
+        assert_eq!(render(Format::Json, false, &[]), "[]\n");
+        assert_eq!(
+            render(Format::Ndjson, true, &records[..1]),
+            "{\"City\":\"Tokyo\",\"Note\":\"say \\\"hi\\\"\"}\n"
+        );
+        assert_eq!(
+            render(Format::Markdown, false, records),
+            "| City | Note |\n| --- | --- |\n| Tokyo | say \"hi\" |\n| Paris | a\\|b<br>c |\n"
+        );
+        assert_eq!(render(Format::Tsv, false, &records[..1]), "City\tNote\nTokyo\t\"say \"\"hi\"\"\"\n");
+        assert_eq!(render(Format::Csv, true, &[]), "");
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("NDJSON".parse::<Format>().unwrap(), Format::Ndjson);
+        assert_eq!("md".parse::<Format>().unwrap(), Format::Markdown);
+        assert!("xml".parse::<Format>().is_err());
+        assert_eq!(Format::from_path("logs/2024.ndjson.gz"), Some(Format::Ndjson));
+        assert_eq!(Format::from_path("archive.gz"), None);
+    }
+}
@@ -0,0 +1,299 @@
+use super::Error;
+use super::format::Output;
+use super::parse::Source;
+use super::stats::format_number;
+use super::transform::{field, position, positions};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+/// A function computed over the records of a group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    /// Records in the group, or non-empty values of a column.
+    Count,
+    Sum,
+    Mean,
+    /// Smallest value, compared as numbers when every value is one.
+    Min,
+    Max,
+    /// Distinct non-empty values.
+    Distinct,
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Mean => "mean",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Distinct => "distinct",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Function {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "count" => Ok(Function::Count),
+            "sum" => Ok(Function::Sum),
+            "mean" | "avg" => Ok(Function::Mean),
+            "min" => Ok(Function::Min),
+            "max" => Ok(Function::Max),
+            "distinct" => Ok(Function::Distinct),
+            _ => Err("aggregate must be one of count, sum, mean, min, max, distinct"),
+        }
+    }
+}
+
+/// A function over a column, written in the output as `function(column)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    function: Function,
+    column: Option<String>,
+    index: Option<usize>,
+}
+
+impl Aggregate {
+    /// Only `Count` may go without a column.
+    pub fn new(function: Function, column: Option<&str>) -> Result<Self, Error> {
+        if column.is_none() && function != Function::Count {
+            return Err(Error::Data(format!("{} needs a column, e.g. {}:Age", function, function)));
+        }
+        Ok(Aggregate { function, column: column.map(String::from), index: None })
+    }
+
+    /// Parses `FUNCTION:COLUMN`, or `count` alone.
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        match spec.find(':') {
+            Some(i) => Aggregate::new(spec[..i].parse()?, Some(&spec[i + 1..])),
+            None => Aggregate::new(spec.parse()?, None),
+        }
+    }
+
+    fn name(&self) -> String {
+        match &self.column {
+            Some(column) => format!("{}({})", self.function, column),
+            None => self.function.to_string(),
+        }
+    }
+}
+
+/// The running value of one aggregate in one group.
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    sum: f64,
+    numeric: bool,
+    min: Option<(f64, String)>,
+    max: Option<(f64, String)>,
+    min_text: Option<String>,
+    max_text: Option<String>,
+    values: HashSet<String>,
+}
+
+impl Accumulator {
+    fn new() -> Self {
+        Accumulator { numeric: true, ..Accumulator::default() }
+    }
+
+    fn add(&mut self, function: Function, column: &str, value: Option<&str>) -> Result<(), Error> {
+        let value = match value.map(str::trim) {
+            None => {
+                self.count += 1;
+                return Ok(());
+            }
+            Some("") => return Ok(()),
+            Some(value) => value,
+        };
+        self.count += 1;
+        let number = value.parse::<f64>().ok().filter(|n| n.is_finite());
+        match function {
+            Function::Count => {}
+            Function::Sum | Function::Mean => match number {
+                Some(number) => self.sum += number,
+                None => return Err(Error::Data(format!("column `{}`: `{}` is not a number", column, value))),
+            },
+            Function::Min | Function::Max => {
+                let wanted = if function == Function::Min { Ordering::Less } else { Ordering::Greater };
+                let (extreme, text) = if function == Function::Min {
+                    (&mut self.min, &mut self.min_text)
+                } else {
+                    (&mut self.max, &mut self.max_text)
+                };
+                match number {
+                    Some(number) => {
+                        if extreme.as_ref().is_none_or(|(n, _)| number.partial_cmp(n) == Some(wanted)) {
+                            *extreme = Some((number, value.to_string()));
+                        }
+                    }
+                    None => self.numeric = false,
+                }
+                if text.as_deref().is_none_or(|text| value.cmp(text) == wanted) {
+                    *text = Some(value.to_string());
+                }
+            }
+            Function::Distinct => {
+                if !self.values.contains(value) {
+                    self.values.insert(value.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn result(&self, function: Function) -> String {
+        match function {
+            Function::Count => self.count.to_string(),
+            Function::Sum => format_number(self.sum),
+            Function::Mean if self.count == 0 => String::new(),
+            Function::Mean => format_number(self.sum / self.count as f64),
+            Function::Min | Function::Max => {
+                let (number, text) = if function == Function::Min {
+                    (&self.min, &self.min_text)
+                } else {
+                    (&self.max, &self.max_text)
+                };
+                if self.numeric {
+                    number.as_ref().map(|(_, value)| value.clone()).unwrap_or_default()
+                } else {
+                    text.clone().unwrap_or_default()
+                }
+            }
+            Function::Distinct => self.values.len().to_string(),
+        }
+    }
+}
+
+/// Groups records by key columns and computes aggregates over each group.
+///
+/// Groups are written in the order their key first appears, one record
+/// each with the key columns followed by one column per aggregate. Empty
+/// values are left out of every aggregate but a plain `count`.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Aggregate, Function, GroupBy, Writer};
+/// let data = "City,Age\nTokyo,32\nParis,27\nTokyo,40\n";
+/// let mut writer = Writer::new(Vec::new());
+/// GroupBy::new(&["City"])
+///     .aggregate(Aggregate::new(Function::Count, None).unwrap())
+///     .aggregate(Aggregate::parse("mean:Age").unwrap())
+///     .group(parse_records(data), &mut writer)
+///     .unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "City,count,mean(Age)\nTokyo,2,36\nParis,1,27\n");
+/// ```
+pub struct GroupBy {
+    columns: Vec<String>,
+    aggregates: Vec<Aggregate>,
+}
+
+impl GroupBy {
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        let columns = columns.iter().map(|c| c.as_ref().to_string()).collect();
+        GroupBy { columns, aggregates: Vec::new() }
+    }
+
+    pub fn aggregate(mut self, aggregate: Aggregate) -> Self {
+        self.aggregates.push(aggregate);
+        self
+    }
+
+    /// Writes one record per group to `output`, returning the number of
+    /// groups.
+    pub fn group<S: Source, O: Output + ?Sized>(&mut self, mut reader: S, output: &mut O) -> Result<u64, Error> {
+        let headers = reader.headers()?;
+        let keys = positions(&headers, &self.columns)?;
+        for aggregate in &mut self.aggregates {
+            aggregate.index = match &aggregate.column {
+                Some(column) => Some(position(&headers, column)?),
+                None => None,
+            };
+        }
+        let mut out_headers: Vec<String> = keys.iter().map(|&i| headers[i].clone()).collect();
+        out_headers.extend(self.aggregates.iter().map(Aggregate::name));
+        output.write_headers(&out_headers, reader.synthetic_headers())?;
+
+        let mut groups: HashMap<Vec<String>, usize> = HashMap::new();
+        let mut accumulators: Vec<(Vec<String>, Vec<Accumulator>)> = Vec::new();
+        while let Some(record) = reader.read_record()? {
+            let mut add = || -> Result<(), Error> {
+                let key = keys.iter().map(|&i| field(&record, i).cloned()).collect::<Result<Vec<_>, _>>()?;
+                let group = *groups.entry(key).or_insert_with_key(|key| {
+                    let group = self.aggregates.iter().map(|_| Accumulator::new()).collect();
+                    accumulators.push((key.clone(), group));
+                    accumulators.len() - 1
+                });
+                for (aggregate, accumulator) in self.aggregates.iter().zip(&mut accumulators[group].1) {
+                    let value = match aggregate.index {
+                        Some(index) => Some(field(&record, index)?.as_str()),
+                        None => None,
+                    };
+                    let column = aggregate.column.as_deref().unwrap_or_default();
+                    accumulator.add(aggregate.function, column, value)?;
+                }
+                Ok(())
+            };
+            add().map_err(|e| e.at(reader.record_position()))?;
+        }
+        for (key, group) in &accumulators {
+            let mut record = key.clone();
+            for (aggregate, accumulator) in self.aggregates.iter().zip(group) {
+                record.push(accumulator.result(aggregate.function));
+            }
+            output.write_record(&record)?;
+        }
+        output.finish()?;
+        Ok(accumulators.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aggregate, GroupBy};
+    use crate::core::parse::{parse_records, Writer};
+
+    fn group(columns: &[&str], aggregates: &[&str], data: &str) -> Result<String, crate::err::Error> {
+        let mut group_by = GroupBy::new(columns);
+        for aggregate in aggregates {
+            group_by = group_by.aggregate(Aggregate::parse(aggregate)?);
+        }
+        let mut writer = Writer::new(Vec::new());
+        group_by.group(parse_records(data), &mut writer)?;
+        Ok(String::from_utf8(writer.into_inner().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let data = "City,Kind,Age,Name\nTokyo,a,32,John\nParis,b,9,Amy\nTokyo,a,,Brad\nTokyo,b,1.5,John\nParis,b,10,Zoe\n";
+        let all = ["count", "count:Age", "sum:Age", "mean:Age", "min:Age", "max:Age", "distinct:Name", "max:Name"];
+        assert_eq!(
+            group(&["City"], &all, data).unwrap(),
+            "City,count,count(Age),sum(Age),mean(Age),min(Age),max(Age),distinct(Name),max(Name)\n\
+             Tokyo,3,2,33.5,16.75,1.5,32,2,John\nParis,2,2,19,9.5,9,10,2,Zoe\n"
+        );
+        assert_eq!(
+            group(&["City", "Kind"], &["count"], data).unwrap(),
+            "City,Kind,count\nTokyo,a,2\nParis,b,2\nTokyo,b,1\n"
+        );
+        // Values that aren't all numbers compare as text.
+        assert_eq!(group(&["City"], &["min:Age"], "City,Age\nX,10\nX,9\nX,n/a\n").unwrap(), "City,min(Age)\nX,10\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let e = group(&["City"], &["sum:Name"], "City,Name\nTokyo,John\n").unwrap_err();
+        assert_eq!(e.to_string(), "<input>:2: column `Name`: `John` is not a number\n    Tokyo,John");
+        assert!(Aggregate::parse("sum").is_err());
+        assert!(Aggregate::parse("median:Age").is_err());
+        assert!(group(&["Town"], &["count"], "City\nTokyo\n").is_err());
+    }
+}
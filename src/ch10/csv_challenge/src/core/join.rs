@@ -0,0 +1,458 @@
+use super::{Error, PathBuf};
+use super::format::Output;
+use super::parse::Source;
+use super::sort::{record_size, Entry, ExternalSorter, DEFAULT_MEMORY_LIMIT};
+use super::transform::positions;
+use crate::err::Position;
+use std::{cmp::Ordering, collections::HashMap, env, str::FromStr};
+
+/// Which records a `Join` writes besides the matching ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JoinKind {
+    /// Only records whose key is in both inputs.
+    Inner,
+    /// Every left record, with empty right columns when unmatched.
+    Left,
+    /// Every record of both inputs.
+    Full,
+}
+
+impl FromStr for JoinKind {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "inner" => Ok(JoinKind::Inner),
+            "left" => Ok(JoinKind::Left),
+            "full" | "outer" => Ok(JoinKind::Full),
+            _ => Err("join kind must be one of inner, left, full"),
+        }
+    }
+}
+
+/// What to do with a key found in several right records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Duplicates {
+    /// Pair the left record with every right one.
+    All,
+    /// Use the first right record of the key.
+    First,
+    /// Stop with an error.
+    Fail,
+}
+
+impl FromStr for Duplicates {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(Duplicates::All),
+            "first" => Ok(Duplicates::First),
+            "fail" => Ok(Duplicates::Fail),
+            _ => Err("duplicates must be one of all, first, fail"),
+        }
+    }
+}
+
+/// Joins two inputs on key columns.
+///
+/// The right input is loaded into a hash table when it fits the memory
+/// limit, and the left one streamed in its order. Otherwise both are
+/// sorted on disk by key and merged, so the output comes in key order.
+///
+/// The output has the left columns followed by the right ones but the
+/// keys. Right columns whose name is taken get the suffix, `_right` by
+/// default.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Join, JoinKind, Writer};
+/// let people = "Name,City\nJohn,Tokyo\nFlip,Atlantis\n";
+/// let codes = "Town,Code\nTokyo,TYO\n";
+/// let mut writer = Writer::new(Vec::new());
+/// Join::new(JoinKind::Left, &[("City", "Town")])
+///     .join(parse_records(people), parse_records(codes), &mut writer)
+///     .unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "Name,City,Code\nJohn,Tokyo,TYO\nFlip,Atlantis,\n");
+/// ```
+pub struct Join {
+    kind: JoinKind,
+    on: Vec<(String, String)>,
+    duplicates: Duplicates,
+    suffix: String,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+}
+
+impl Join {
+    /// Joins on pairs of left and right key columns.
+    pub fn new<S: AsRef<str>>(kind: JoinKind, on: &[(S, S)]) -> Self {
+        Join {
+            kind,
+            on: on.iter().map(|(l, r)| (l.as_ref().to_string(), r.as_ref().to_string())).collect(),
+            duplicates: Duplicates::All,
+            suffix: "_right".to_string(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            temp_dir: env::temp_dir(),
+        }
+    }
+
+    /// Parses a key as `COLUMN`, named the same in both inputs, or as
+    /// `LEFT=RIGHT`.
+    pub fn parse_key(spec: &str) -> (String, String) {
+        match spec.find('=') {
+            Some(i) => (spec[..i].to_string(), spec[i + 1..].to_string()),
+            None => (spec.to_string(), spec.to_string()),
+        }
+    }
+
+    pub fn duplicates(mut self, duplicates: Duplicates) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn suffix(mut self, suffix: &str) -> Self {
+        self.suffix = suffix.to_string();
+        self
+    }
+
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    pub fn temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    /// Writes the joined records to `output`, returning how many.
+    pub fn join<L, R, O>(&self, mut left: L, mut right: R, output: &mut O) -> Result<u64, Error>
+    where
+        L: Source,
+        R: Source,
+        O: Output + ?Sized,
+    {
+        if self.on.is_empty() {
+            Err("join needs at least one key column")?
+        }
+        let left_headers = left.headers()?;
+        let right_headers = right.headers()?;
+        let left_columns: Vec<String> = self.on.iter().map(|(l, _)| l.clone()).collect();
+        let right_columns: Vec<String> = self.on.iter().map(|(_, r)| r.clone()).collect();
+        let left_keys = positions(&left_headers, &left_columns)?;
+        let right_keys = positions(&right_headers, &right_columns)?;
+        let rest: Vec<usize> = (0..right_headers.len()).filter(|i| !right_keys.contains(i)).collect();
+        let mut headers = left_headers.clone();
+        for &i in &rest {
+            let mut name = right_headers[i].clone();
+            if headers.contains(&name) {
+                name.push_str(&self.suffix);
+            }
+            if headers.contains(&name) {
+                return Err(Error::Data(format!("column `{}` is in both inputs", right_headers[i])));
+            }
+            headers.push(name);
+        }
+        output.write_headers(&headers, left.synthetic_headers())?;
+        let mut rows = Rows {
+            output,
+            kind: self.kind,
+            left_width: left_headers.len(),
+            left_keys: &left_keys,
+            right_keys: &right_keys,
+            rest: &rest,
+            written: 0,
+        };
+
+        // Load the right input while it fits in memory.
+        let mut table: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+        let mut records: Vec<Entry> = Vec::new();
+        let mut bytes = 0;
+        while bytes < self.memory_limit {
+            let record = match right.read_record()? {
+                Some(record) => record,
+                None => break,
+            };
+            let matches = table.entry(key(&record, &right_keys)).or_default();
+            if !matches.is_empty() {
+                match self.duplicates {
+                    Duplicates::All => {}
+                    Duplicates::First => continue,
+                    Duplicates::Fail => return Err(duplicate(&record, &right_keys).at(right.record_position())),
+                }
+            }
+            bytes += record_size(&record);
+            matches.push(records.len());
+            records.push((self.sequence(&right, records.len() as u64), record));
+        }
+        if bytes < self.memory_limit {
+            let mut matched = vec![false; records.len()];
+            while let Some(record) = left.read_record()? {
+                let found = table.get(&key(&record, &left_keys)).map(Vec::as_slice).unwrap_or_default();
+                if found.is_empty() {
+                    rows.left_only(&record)?;
+                }
+                for &i in found {
+                    matched[i] = true;
+                    rows.matched(&record, &records[i].1)?;
+                }
+            }
+            for ((_, record), matched) in records.iter().zip(matched) {
+                if !matched {
+                    rows.right_only(record)?;
+                }
+            }
+        } else {
+            self.merge_join(left, right, records, &mut rows)?;
+        }
+        rows.output.finish()?;
+        Ok(rows.written)
+    }
+
+    /// The number ordering a right record read after `count` others
+    /// among equal keys. With `Duplicates::Fail` no key repeats, so it is
+    /// the record's line instead, to locate a duplicate found by merging.
+    fn sequence<R: Source>(&self, right: &R, count: u64) -> u64 {
+        match self.duplicates {
+            Duplicates::Fail => right.record_position().line,
+            _ => count,
+        }
+    }
+
+    /// Sorts both inputs by key on disk and merges them.
+    fn merge_join<L: Source, R: Source, O: Output + ?Sized>(
+        &self,
+        mut left: L,
+        mut right: R,
+        loaded: Vec<Entry>,
+        rows: &mut Rows<O>,
+    ) -> Result<(), Error> {
+        let (left_keys, right_keys) = (rows.left_keys, rows.right_keys);
+        let by_left_key = |a: &[String], b: &[String]| compare_keys(a, b, left_keys, left_keys);
+        let by_right_key = |a: &[String], b: &[String]| compare_keys(a, b, right_keys, right_keys);
+        let mut sorted_right = ExternalSorter::new(by_right_key, self.memory_limit, &self.temp_dir)?;
+        let mut seq = loaded.len() as u64;
+        for (seq, record) in loaded {
+            sorted_right.push(seq, record)?;
+        }
+        while let Some(record) = right.read_record()? {
+            sorted_right.push(self.sequence(&right, seq), record)?;
+            seq += 1;
+        }
+        let duplicate_at = |(line, record): &Entry| {
+            let file = right.name().map(String::from);
+            duplicate(record, right_keys).at(Position { file, line: *line, ..Position::default() })
+        };
+        let mut sorted_left = ExternalSorter::new(by_left_key, self.memory_limit, &self.temp_dir)?;
+        let mut seq = 0;
+        while let Some(record) = left.read_record()? {
+            sorted_left.push(seq, record)?;
+            seq += 1;
+        }
+
+        let mut lefts = sorted_left.finish()?;
+        let mut rights = sorted_right.finish()?;
+        let mut next_left = lefts.next().transpose()?;
+        let mut next_right = rights.next().transpose()?;
+        // The right records of the current key.
+        let mut group: Vec<Entry> = Vec::new();
+        loop {
+            let ordering = match (&next_left, &next_right) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(l), Some(r)) => compare_keys(&l.1, &r.1, left_keys, right_keys),
+            };
+            match ordering {
+                Ordering::Less => {
+                    if let Some((_, record)) = next_left.take() {
+                        rows.left_only(&record)?;
+                    }
+                    next_left = lefts.next().transpose()?;
+                }
+                Ordering::Greater => {
+                    if let Some((_, record)) = next_right.take() {
+                        rows.right_only(&record)?;
+                        next_right = rights.next().transpose()?;
+                        if let (Duplicates::Fail, Some(next)) = (self.duplicates, &next_right) {
+                            if compare_keys(&record, &next.1, right_keys, right_keys).is_eq() {
+                                return Err(duplicate_at(next));
+                            }
+                        }
+                    }
+                }
+                Ordering::Equal => {
+                    group.clear();
+                    while let Some(entry) = next_right.take() {
+                        if let Some(first) = group.first() {
+                            if !compare_keys(&first.1, &entry.1, right_keys, right_keys).is_eq() {
+                                next_right = Some(entry);
+                                break;
+                            }
+                            if self.duplicates == Duplicates::Fail {
+                                return Err(duplicate_at(&entry));
+                            }
+                        }
+                        group.push(entry);
+                        next_right = rights.next().transpose()?;
+                    }
+                    if self.duplicates == Duplicates::First {
+                        group.truncate(1);
+                    }
+                    while let Some((_, record)) = next_left.take() {
+                        if !compare_keys(&record, &group[0].1, left_keys, right_keys).is_eq() {
+                            next_left = Some((0, record));
+                            break;
+                        }
+                        for (_, right) in &group {
+                            rows.matched(&record, right)?;
+                        }
+                        next_left = lefts.next().transpose()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn key(record: &[String], keys: &[usize]) -> Vec<String> {
+    keys.iter().map(|&i| record[i].clone()).collect()
+}
+
+fn compare_keys(a: &[String], b: &[String], a_keys: &[usize], b_keys: &[usize]) -> Ordering {
+    a_keys
+        .iter()
+        .zip(b_keys)
+        .map(|(&i, &j)| a[i].cmp(&b[j]))
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+fn duplicate(record: &[String], keys: &[usize]) -> Error {
+    Error::Data(format!("duplicate key `{}` in the right input", key(record, keys).join(",")))
+}
+
+/// Writes joined records.
+struct Rows<'a, O: ?Sized> {
+    output: &'a mut O,
+    kind: JoinKind,
+    left_width: usize,
+    left_keys: &'a [usize],
+    right_keys: &'a [usize],
+    rest: &'a [usize],
+    written: u64,
+}
+
+impl<O: Output + ?Sized> Rows<'_, O> {
+    fn write(&mut self, record: &[String]) -> Result<(), Error> {
+        self.written += 1;
+        self.output.write_record(record)
+    }
+
+    fn matched(&mut self, left: &[String], right: &[String]) -> Result<(), Error> {
+        let mut record = left.to_vec();
+        record.extend(self.rest.iter().map(|&i| right[i].clone()));
+        self.write(&record)
+    }
+
+    fn left_only(&mut self, left: &[String]) -> Result<(), Error> {
+        if self.kind == JoinKind::Inner {
+            return Ok(());
+        }
+        let mut record = left.to_vec();
+        record.resize(self.left_width + self.rest.len(), String::new());
+        self.write(&record)
+    }
+
+    /// The key goes into the left key columns, the only left columns
+    /// known.
+    fn right_only(&mut self, right: &[String]) -> Result<(), Error> {
+        if self.kind != JoinKind::Full {
+            return Ok(());
+        }
+        let mut record = vec![String::new(); self.left_width];
+        for (&l, &r) in self.left_keys.iter().zip(self.right_keys) {
+            record[l] = right[r].clone();
+        }
+        record.extend(self.rest.iter().map(|&i| right[i].clone()));
+        self.write(&record)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Duplicates, Join, JoinKind};
+    use crate::core::parse::{parse_records, Writer};
+    use std::{fs, path::PathBuf};
+
+    const LEFT: &str = "Name,City\nJohn,Tokyo\nFlip,Atlantis\nBrad,Paris\nAmy,Tokyo\n";
+    const RIGHT: &str = "City,Code,Name\nParis,PAR,Ville\nTokyo,TYO,Tokyo-to\nDublin,DUB,\nParis,PRS,Lutece\n";
+
+    fn join(join: Join, memory_limit: usize) -> Result<String, crate::err::Error> {
+        fs::create_dir_all("output/join").unwrap();
+        let mut writer = Writer::new(Vec::new());
+        join.memory_limit(memory_limit)
+            .temp_dir(PathBuf::from("output/join"))
+            .join(parse_records(LEFT), parse_records(RIGHT), &mut writer)?;
+        Ok(String::from_utf8(writer.into_inner().unwrap()).unwrap())
+    }
+
+    fn sorted_lines(output: &str) -> Vec<&str> {
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines[1..].sort();
+        lines
+    }
+
+    #[test]
+    fn test_hash_join() {
+        let inner = join(Join::new(JoinKind::Inner, &[("City", "City")]), usize::MAX).unwrap();
+        assert_eq!(
+            inner,
+            "Name,City,Code,Name_right\nJohn,Tokyo,TYO,Tokyo-to\nBrad,Paris,PAR,Ville\n\
+             Brad,Paris,PRS,Lutece\nAmy,Tokyo,TYO,Tokyo-to\n"
+        );
+        let first = Join::new(JoinKind::Left, &[("City", "City")]).duplicates(Duplicates::First);
+        let left = join(first, usize::MAX).unwrap();
+        assert_eq!(
+            left,
+            "Name,City,Code,Name_right\nJohn,Tokyo,TYO,Tokyo-to\nFlip,Atlantis,,\n\
+             Brad,Paris,PAR,Ville\nAmy,Tokyo,TYO,Tokyo-to\n"
+        );
+        let full = join(Join::new(JoinKind::Full, &[("City", "City")]), usize::MAX).unwrap();
+        assert!(full.ends_with("Amy,Tokyo,TYO,Tokyo-to\n,Dublin,DUB,\n"));
+        let fail = Join::new(JoinKind::Inner, &[("City", "City")]).duplicates(Duplicates::Fail);
+        assert_eq!(
+            join(fail, usize::MAX).unwrap_err().to_string(),
+            "<input>:5: duplicate key `Paris` in the right input\n    Paris,PRS,Lutece"
+        );
+        let conflict = Join::new(JoinKind::Inner, &[("City", "City")]).suffix("");
+        assert!(join(conflict, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_merge_join_matches_hash_join() {
+        for kind in &[JoinKind::Inner, JoinKind::Left, JoinKind::Full] {
+            for duplicates in &[Duplicates::All, Duplicates::First] {
+                let make = || Join::new(*kind, &[("City", "City")]).duplicates(*duplicates);
+                let hashed = join(make(), usize::MAX).unwrap();
+                let merged = join(make(), 1).unwrap();
+                assert_eq!(sorted_lines(&merged), sorted_lines(&hashed));
+            }
+        }
+        let fail = || Join::new(JoinKind::Inner, &[("City", "City")]).duplicates(Duplicates::Fail);
+        assert_eq!(
+            join(fail(), 1).unwrap_err().to_string(),
+            "<input>:5: duplicate key `Paris` in the right input"
+        );
+        // A duplicate without a left match is found as well.
+        let left = Join::new(JoinKind::Left, &[("Name", "Name")]).duplicates(Duplicates::Fail);
+        let right = "Name,Age\nZoe,1\nZoe,2\n";
+        let mut writer = Writer::new(Vec::new());
+        let error = left.memory_limit(1)
+            .temp_dir(PathBuf::from("output/join"))
+            .join(parse_records(LEFT), parse_records(right), &mut writer)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "<input>:3: duplicate key `Zoe` in the right input");
+    }
+}
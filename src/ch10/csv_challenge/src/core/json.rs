@@ -0,0 +1,407 @@
+use super::Error;
+use super::parse::Source;
+use crate::err::Position;
+use serde::Deserialize;
+use serde_json::{Deserializer, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read},
+};
+
+/// The fields of a record by column index.
+type Fields = Vec<(usize, String)>;
+
+/// Reads a JSON array of objects, or newline-delimited objects, as
+/// records.
+///
+/// Nested objects are flattened into dotted column names, arrays are kept
+/// as JSON text and `null` becomes an empty field. The header is the union
+/// of all keys in the order they first appear, so the whole input is read
+/// when the header is asked for.
+///
+/// The records are then held in memory, unless the input can be opened
+/// again, see `reopen`: it is read a second time record by record, the
+/// elements of an array one at a time, so inputs of any size take
+/// constant memory. Stdin is always held.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{JsonReader, Source};
+/// let data = "{\"id\":1,\"user\":{\"name\":\"John\"}}\n{\"id\":2,\"tags\":[\"a\"]}\n";
+/// let mut reader = JsonReader::new(data.as_bytes()).reopen(move || Ok(data.as_bytes()));
+/// assert_eq!(reader.headers().unwrap(), vec!["id", "user.name", "tags"]);
+/// assert_eq!(reader.read_record().unwrap().unwrap(), vec!["1", "John", ""]);
+/// assert_eq!(reader.read_record().unwrap().unwrap(), vec!["2", "", "[\"a\"]"]);
+/// ```
+pub struct JsonReader<R> {
+    input: Option<R>,
+    reopen: Option<Box<dyn FnMut() -> Result<R, Error>>>,
+    name: Option<String>,
+    headers: Vec<String>,
+    columns: HashMap<String, usize>,
+    /// Records read and not returned yet.
+    records: VecDeque<(Position, Fields)>,
+    /// The input read the second time.
+    values: Option<Values<R>>,
+    position: Position,
+}
+
+impl<R: Read> JsonReader<R> {
+    pub fn new(input: R) -> Self {
+        JsonReader {
+            input: Some(input),
+            reopen: None,
+            name: None,
+            headers: Vec::new(),
+            columns: HashMap::new(),
+            records: VecDeque::new(),
+            values: None,
+            position: Position::default(),
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// How to open the input again, to stream its records after reading
+    /// the header rather than hold them.
+    pub fn reopen<F: FnMut() -> Result<R, Error> + 'static>(mut self, open: F) -> Self {
+        self.reopen = Some(Box::new(open));
+        self
+    }
+
+    fn load(&mut self, input: R) -> Result<(), Error> {
+        let mut values = Values::new(input, self.name.clone());
+        let keep = self.reopen.is_none();
+        while let Some((line, value)) = values.next()? {
+            let record = self.record_of(line, value, true)?;
+            if keep {
+                self.records.push_back(record);
+            }
+        }
+        if self.headers.is_empty() {
+            Err("input file missing")?
+        }
+        if let (false, Some(reopen)) = (keep, self.reopen.as_mut()) {
+            self.values = Some(Values::new(reopen()?, self.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// The record `value` found on `line` is, adding the columns it
+    /// brings to the header if `grow`.
+    fn record_of(&mut self, line: u64, value: Value, grow: bool) -> Result<(Position, Fields), Error> {
+        let position = self.locate(line, &value);
+        if !value.is_object() {
+            return Err(Error::Data("expected a JSON object per record".to_string()).at(position));
+        }
+        let mut fields = Vec::new();
+        flatten(String::new(), value, &mut fields);
+        let mut record = Vec::with_capacity(fields.len());
+        for (key, field) in fields {
+            let index = match self.columns.get(&key) {
+                Some(&index) => index,
+                None if grow => {
+                    self.headers.push(key.clone());
+                    self.columns.insert(key, self.headers.len() - 1);
+                    self.headers.len() - 1
+                }
+                None => return Err(Error::Data("input changed while being read".to_string()).at(position)),
+            };
+            record.push((index, field));
+        }
+        Ok((position, record))
+    }
+
+    fn locate(&self, line: u64, value: &Value) -> Position {
+        Position { file: self.name.clone(), line, column: None, record: Some(value.to_string()) }
+    }
+}
+
+/// Input counting the lines and columns it is read through.
+struct Counted<R> {
+    input: BufReader<R>,
+    line: u64,
+    column: usize,
+}
+
+impl<R: Read> BufRead for Counted<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        for &byte in &self.input.buffer()[..amount] {
+            if byte == b'\n' {
+                self.line += 1;
+                self.column = 0;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.input.consume(amount);
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+/// Where `Values` is: between top-level values, or in a top-level array
+/// before its first element, after an element or after a comma.
+#[derive(Clone, Copy, PartialEq)]
+enum Nesting {
+    Top,
+    ArrayStart,
+    AfterElement,
+    AfterComma,
+}
+
+/// The JSON values of an input, the elements of top-level arrays one by
+/// one, and the lines they start on. Values are parsed straight from the
+/// input, which the parser reads no further than their end.
+struct Values<R> {
+    input: Counted<R>,
+    name: Option<String>,
+    nesting: Nesting,
+}
+
+impl<R: Read> Values<R> {
+    fn new(input: R, name: Option<String>) -> Self {
+        let input = Counted { input: BufReader::new(input), line: 1, column: 0 };
+        Values { input, name, nesting: Nesting::Top }
+    }
+
+    fn next(&mut self) -> Result<Option<(u64, Value)>, Error> {
+        loop {
+            match (self.nesting, self.peek()?) {
+                (Nesting::Top, None) => return Ok(None),
+                (Nesting::Top, Some(b'[')) => self.step(Nesting::ArrayStart),
+                (Nesting::ArrayStart, Some(b']')) | (Nesting::AfterElement, Some(b']')) => self.step(Nesting::Top),
+                (Nesting::AfterElement, Some(b',')) => self.step(Nesting::AfterComma),
+                (_, None) => return Err(self.error("EOF while parsing a list")),
+                (Nesting::AfterElement, Some(_)) => return Err(self.error("expected `,` or `]`")),
+                (Nesting::AfterComma, Some(b']')) => return Err(self.error("trailing comma")),
+                (nesting, Some(_)) => {
+                    let line = self.input.line;
+                    let value = self.value()?;
+                    if nesting != Nesting::Top {
+                        self.nesting = Nesting::AfterElement;
+                    }
+                    return Ok(Some((line, value)));
+                }
+            }
+        }
+    }
+
+    /// Skips whitespace, returning the byte after it.
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            let (blank, next) = {
+                let buf = self.input.fill_buf()?;
+                let blank = buf.iter().take_while(|b| matches!(b, b' ' | b'\t' | b'\n' | b'\r')).count();
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                (blank, buf.get(blank).copied())
+            };
+            self.input.consume(blank);
+            if next.is_some() {
+                return Ok(next);
+            }
+        }
+    }
+
+    fn step(&mut self, nesting: Nesting) {
+        self.input.consume(1);
+        self.nesting = nesting;
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        let (line, column) = (self.input.line, self.input.column);
+        let value = Value::deserialize(&mut Deserializer::from_reader(&mut self.input));
+        value.map_err(|e| {
+            if e.is_io() {
+                return Error::from(io::Error::from(e)).at(self.position(self.input.line, None));
+            }
+            let message = e.to_string();
+            let message = message.split(" at line ").next().unwrap_or_default();
+            let column = if e.line() == 1 { column + e.column() } else { e.column() };
+            let position = self.position(line + e.line() as u64 - 1, Some(column));
+            Error::Data(format!("invalid JSON: {}", message)).at(position)
+        })
+    }
+
+    fn error(&self, message: &str) -> Error {
+        let position = self.position(self.input.line, Some(self.input.column + 1));
+        Error::Data(format!("invalid JSON: {}", message)).at(position)
+    }
+
+    fn position(&self, line: u64, column: Option<usize>) -> Position {
+        Position { file: self.name.clone(), line, column, record: None }
+    }
+}
+
+/// Appends the fields of `value` to `fields`, nested object keys joined
+/// to `key` with dots.
+fn flatten(key: String, value: Value, fields: &mut Vec<(String, String)>) {
+    match value {
+        Value::Object(object) => {
+            if object.is_empty() && !key.is_empty() {
+                fields.push((key, String::new()));
+                return;
+            }
+            for (name, value) in object {
+                let name = if key.is_empty() { name } else { format!("{}.{}", key, name) };
+                flatten(name, value, fields);
+            }
+        }
+        Value::Null => fields.push((key, String::new())),
+        Value::String(s) => fields.push((key, s)),
+        value => fields.push((key, value.to_string())),
+    }
+}
+
+impl<R: Read> Source for JsonReader<R> {
+    fn headers(&mut self) -> Result<Vec<String>, Error> {
+        if let Some(input) = self.input.take() {
+            self.load(input)?;
+        }
+        Ok(self.headers.clone())
+    }
+
+    fn read_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        if self.input.is_some() {
+            self.headers()?;
+        }
+        loop {
+            if let Some((position, fields)) = self.records.pop_front() {
+                self.position = position;
+                let mut record = vec![String::new(); self.headers.len()];
+                for (index, field) in fields {
+                    record[index] = field;
+                }
+                return Ok(Some(record));
+            }
+            let next = match self.values.as_mut() {
+                Some(values) => values.next()?,
+                None => return Ok(None),
+            };
+            match next {
+                Some((line, value)) => {
+                    let record = self.record_of(line, value, false)?;
+                    self.records.push_back(record);
+                }
+                None => self.values = None,
+            }
+        }
+    }
+
+    fn record_position(&self) -> Position {
+        self.position.clone()
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::JsonReader;
+    use crate::core::parse::Source;
+    use serde_json::Value;
+    use std::{cell::Cell, io::Cursor, rc::Rc};
+
+    fn read(data: &str) -> (Vec<String>, Vec<Vec<String>>) {
+        let mut reader = JsonReader::new(data.as_bytes());
+        let headers = reader.headers().unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = reader.read_record().unwrap() {
+            records.push(record);
+        }
+        (headers, records)
+    }
+
+    #[test]
+    fn test_array_and_lines() {
+        let array = r#"[{"a": 1, "b": {"c": true, "d": null}}, {"e": "x", "a": 2.5}]"#;
+        let (headers, records) = read(array);
+        assert_eq!(headers, vec!["a", "b.c", "b.d", "e"]);
+        assert_eq!(records, vec![vec!["1", "true", "", ""], vec!["2.5", "", "", "x"]]);
+
+        let lines = "{\"a\":1,\"b\":{\"c\":true,\"d\":null}}\n\n{\"e\":\"x\",\"a\":2.5}\n";
+        assert_eq!(read(lines), (headers, records));
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut reader = JsonReader::new("{\"a\":1}\n\n{\"a\":2}\n".as_bytes()).with_name("log.ndjson");
+        reader.read_record().unwrap();
+        reader.read_record().unwrap();
+        assert_eq!(reader.record_position().to_string(), "log.ndjson:3");
+
+        let mut reader = JsonReader::new("{\"a\":1}\n{\"a\":2,}\n".as_bytes());
+        let e = reader.headers().unwrap_err();
+        assert_eq!(e.to_string(), "<input>:2:8: invalid JSON: trailing comma");
+        let e = JsonReader::new("{\"a\":1}\n3\n".as_bytes()).headers().unwrap_err();
+        assert_eq!(e.to_string(), "<input>:2: expected a JSON object per record\n    3");
+        assert!(JsonReader::new("".as_bytes()).headers().is_err());
+    }
+
+    #[test]
+    fn test_streamed_second_pass() {
+        let lines = "{\"a\":1}\n{\n  \"b\": {\"c\": 2}\n}\n\n{\"a\":3}\n";
+        let opens = Rc::new(Cell::new(0));
+        let counter = opens.clone();
+        let mut reader = JsonReader::new(lines.as_bytes()).reopen(move || {
+            counter.set(counter.get() + 1);
+            Ok(lines.as_bytes())
+        });
+        assert_eq!(reader.headers().unwrap(), vec!["a", "b.c"]);
+        assert_eq!((opens.get(), reader.records.len()), (1, 0));
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec!["1", ""]);
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec!["", "2"]);
+        assert_eq!(reader.record_position().line, 2);
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec!["3", ""]);
+        assert_eq!(reader.record_position().line, 6);
+        assert!(reader.read_record().unwrap().is_none());
+
+        // An array is read again element by element too.
+        let array = "[{\"a\":1},{\"a\":2}]";
+        let opens = Rc::new(Cell::new(0));
+        let counter = opens.clone();
+        let mut reader = JsonReader::new(array.as_bytes()).reopen(move || {
+            counter.set(counter.get() + 1);
+            Ok(array.as_bytes())
+        });
+        reader.headers().unwrap();
+        assert_eq!((opens.get(), reader.records.len()), (1, 0));
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec!["1"]);
+        assert_eq!(reader.read_record().unwrap().unwrap(), vec!["2"]);
+        assert!(reader.read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_pretty_array() {
+        let records: Vec<Value> = (0..5000u64).map(|i| serde_json::json!({ "id": i, "tags": { "even": i.is_multiple_of(2) } })).collect();
+        let pretty = serde_json::to_string_pretty(&records).unwrap();
+        let open = move || Ok(Cursor::new(pretty.clone()));
+        let mut reader = JsonReader::new(open().unwrap()).reopen(open);
+        assert_eq!(reader.headers().unwrap(), vec!["id", "tags.even"]);
+        let mut count = 0u64;
+        while let Some(record) = reader.read_record().unwrap() {
+            assert_eq!(record, vec![count.to_string(), count.is_multiple_of(2).to_string()]);
+            // "[" on line 1, then six lines per element.
+            assert_eq!(reader.record_position().line, 2 + 6 * count);
+            count += 1;
+        }
+        assert_eq!(count, 5000);
+    }
+}
@@ -0,0 +1,357 @@
+use super::Error;
+use super::dialect::{Dialect, Escape, Terminator, Trim};
+use super::format::Output;
+use super::parse::Reader;
+use super::ragged::RaggedSummary;
+use super::transform::Transform;
+use crate::err::Position;
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Read},
+    str,
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+/// Bytes of input handed to a worker at a time.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Splits a CSV stream into chunks of whole records, following quoting
+/// so line breaks inside quoted fields never end a chunk.
+struct Chunker<R> {
+    input: R,
+    dialect: Dialect,
+    size: usize,
+    /// Bytes read past the end of the last chunk.
+    rest: Vec<u8>,
+    /// How far `rest` has been scanned.
+    scanner: Scanner,
+    /// The line the next chunk starts at.
+    line: u64,
+}
+
+impl<R: BufRead> Chunker<R> {
+    /// The next chunk and the line it starts at.
+    fn next_chunk(&mut self) -> Result<Option<(Vec<u8>, u64)>, Error> {
+        let mut chunk = std::mem::take(&mut self.rest);
+        let end = loop {
+            let read = chunk.len();
+            (&mut self.input).take(self.size as u64).read_to_end(&mut chunk)?;
+            if chunk.len() == read {
+                // The end of the input ends the last record.
+                self.scanner = Scanner::default();
+                break chunk.len();
+            }
+            if let Some(end) = self.scanner.record_end(&chunk, &self.dialect) {
+                self.scanner.consume(end);
+                break end;
+            }
+        };
+        if end == 0 {
+            return Ok(None);
+        }
+        self.rest = chunk.split_off(end);
+        let line = self.line;
+        let terminator = if self.dialect.terminator == Terminator::Cr { b'\r' } else { b'\n' };
+        self.line += chunk.iter().filter(|&&b| b == terminator).count() as u64;
+        Ok(Some((chunk, line)))
+    }
+}
+
+/// The state of `Reader` from the start of a record, tracked just enough
+/// to tell quoted line breaks from record ends.
+#[derive(Clone, Copy, Default)]
+enum State {
+    #[default]
+    StartField,
+    Unquoted,
+    Quoted,
+    Escaped,
+    Closed,
+}
+
+/// Finds record ends in a buffer that grows between calls, scanning each
+/// byte once however long a quoted field runs.
+#[derive(Default)]
+struct Scanner {
+    state: State,
+    /// Bytes of the buffer scanned so far.
+    offset: usize,
+    /// Where the last complete record scanned ends.
+    last: Option<usize>,
+}
+
+impl Scanner {
+    /// Where the last complete record of `bytes` ends, the bytes scanned
+    /// by earlier calls being unchanged.
+    fn record_end(&mut self, bytes: &[u8], dialect: &Dialect) -> Option<usize> {
+        let end = if dialect.terminator == Terminator::Cr { '\r' } else { '\n' };
+        let escape = match dialect.escape {
+            Escape::Char(c) => Some(c),
+            Escape::Doubled => None,
+        };
+        let trim = matches!(dialect.trim, Trim::Fields | Trim::All);
+        let ascii = dialect.quote.is_ascii() && dialect.delimiter.is_ascii() && escape.is_none_or(|c| c.is_ascii());
+        let start = self.offset;
+        if ascii && !trim {
+            // UTF-8 continuation bytes never look like ASCII, so bytes will do.
+            let chars = bytes[start..].iter().enumerate().map(|(i, &b)| (start + i, b as char));
+            self.scan(chars, dialect, escape, end, false);
+            self.offset = bytes.len();
+        } else {
+            // A character cut at the end is scanned once it is complete.
+            let text = match str::from_utf8(&bytes[start..]) {
+                Ok(text) => text,
+                Err(e) => str::from_utf8(&bytes[start..start + e.valid_up_to()]).unwrap_or_default(),
+            };
+            self.scan(text.char_indices().map(|(i, c)| (start + i, c)), dialect, escape, end, trim);
+            self.offset = start + text.len();
+        }
+        self.last
+    }
+
+    /// Forgets the first `len` bytes of the buffer, up to the last record
+    /// end, as they are cut off.
+    fn consume(&mut self, len: usize) {
+        self.offset -= len;
+        self.last = None;
+    }
+
+    fn scan<I>(&mut self, chars: I, dialect: &Dialect, escape: Option<char>, end: char, trim: bool)
+    where
+        I: Iterator<Item = (usize, char)>,
+    {
+        for (i, c) in chars {
+            self.state = match self.state {
+                State::Quoted if Some(c) == escape => State::Escaped,
+                State::Quoted if c == dialect.quote => State::Closed,
+                State::Quoted => State::Quoted,
+                State::Escaped => State::Quoted,
+                // A doubled quote.
+                State::Closed if c == dialect.quote && escape.is_none() => State::Quoted,
+                State::StartField if c == dialect.quote => State::Quoted,
+                _ if c == end => {
+                    self.last = Some(i + 1);
+                    State::StartField
+                }
+                _ if c == dialect.delimiter => State::StartField,
+                State::StartField if trim && c.is_whitespace() => State::StartField,
+                State::StartField | State::Unquoted => State::Unquoted,
+                State::Closed => State::Closed,
+            };
+        }
+    }
+}
+
+/// What a worker made of one chunk.
+type Done = Result<(Vec<Vec<String>>, RaggedSummary), Error>;
+
+/// Like `transform_stream`, but parses and transforms chunks of the input
+/// on `jobs` threads, writing the records in their original order.
+///
+/// The input is cut at record ends found by a light scan of the quoting,
+/// so only that scan and the writing run on the calling thread. Errors
+/// are located like `transform_stream` does, the first one in input order
+/// being returned.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, transform_parallel, Set, Writer};
+/// let data = "Name,City\nJohn,Tokyo\n\"Doe,\nJane\",Paris\n";
+/// let mut writer = Writer::new(Vec::new());
+/// transform_parallel(parse_records(data), &mut writer, &mut Set::new("City", "Beijing"), 4).unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "Name,City\nJohn,Beijing\n\"Doe,\nJane\",Beijing\n");
+/// ```
+pub fn transform_parallel<R, O, T>(
+    reader: Reader<R>,
+    output: &mut O,
+    transform: &mut T,
+    jobs: usize,
+) -> Result<RaggedSummary, Error>
+where
+    R: BufRead,
+    O: Output + ?Sized,
+    T: Transform + Sync + ?Sized,
+{
+    transform_chunks(reader, output, transform, jobs, CHUNK_SIZE)
+}
+
+fn transform_chunks<R, O, T>(
+    mut reader: Reader<R>,
+    output: &mut O,
+    transform: &mut T,
+    jobs: usize,
+    size: usize,
+) -> Result<RaggedSummary, Error>
+where
+    R: BufRead,
+    O: Output + ?Sized,
+    T: Transform + Sync + ?Sized,
+{
+    let headers = transform.headers(&reader.headers()?).map_err(|e| {
+        e.at(Position { file: reader.name().map(String::from), ..Position::default() })
+    })?;
+    output.write_headers(&headers, reader.synthetic_headers())?;
+    // Records read to size a missing header are written here, the rest
+    // by the workers.
+    let position = reader.record_position();
+    let template = reader.chunk(&[], 1);
+    let (input, pending, lines) = reader.into_rest();
+    if let Some(record) = pending {
+        match transform.apply(record) {
+            Ok(Some(record)) => output.write_record(&record)?,
+            Ok(None) => {}
+            Err(e) => return Err(e.at(position)),
+        }
+    }
+    let dialect = template.dialect().clone();
+    let mut chunker = Chunker { input, dialect, size, rest: Vec::new(), scanner: Scanner::default(), line: lines + 1 };
+    let jobs = jobs.max(1);
+    let transform = &*transform;
+    let (send_chunk, chunks) = mpsc::channel::<(u64, Vec<u8>, u64)>();
+    let chunks = Mutex::new(chunks);
+    let mut summary = RaggedSummary::default();
+
+    thread::scope(|scope| -> Result<(), Error> {
+        let (send_done, done) = mpsc::channel::<(u64, Done)>();
+        for _ in 0..jobs {
+            let (send_done, chunks, template) = (send_done.clone(), &chunks, &template);
+            scope.spawn(move || loop {
+                let next = chunks.lock().map(|chunks| chunks.recv());
+                let (seq, chunk, line) = match next {
+                    Ok(Ok(job)) => job,
+                    _ => return,
+                };
+                let result = run_chunk(template.chunk(&chunk, line), transform);
+                if send_done.send((seq, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        // Workers stop once the chunks run out, or on an early return.
+        let send_chunk = send_chunk;
+
+        let (mut sent, mut written) = (0, 0);
+        let mut finished: BTreeMap<u64, Done> = BTreeMap::new();
+        let mut more = true;
+        loop {
+            // Keep every worker busy, with one chunk waiting for each.
+            while more && sent - written < 2 * jobs as u64 {
+                match chunker.next_chunk()? {
+                    Some((chunk, line)) => {
+                        send_chunk.send((sent, chunk, line)).map_err(|_| "worker threads stopped")?;
+                        sent += 1;
+                    }
+                    None => more = false,
+                }
+            }
+            if written == sent {
+                return Ok(());
+            }
+            let (seq, result) = done.recv().map_err(|_| "worker threads stopped")?;
+            finished.insert(seq, result);
+            while let Some(result) = finished.remove(&written) {
+                let (records, ragged) = result?;
+                for record in &records {
+                    output.write_record(record)?;
+                }
+                summary.append(ragged);
+                written += 1;
+            }
+        }
+    })?;
+    output.finish()?;
+    Ok(summary)
+}
+
+fn run_chunk<T: Transform + ?Sized>(mut reader: Reader<&[u8]>, transform: &T) -> Done {
+    let mut records = Vec::new();
+    while let Some(record) = reader.read_record()? {
+        match transform.apply(record) {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => {}
+            Err(e) => return Err(e.at(reader.record_position())),
+        }
+    }
+    Ok((records, reader.ragged_summary().clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{transform_chunks, Scanner};
+    use crate::core::dialect::{Dialect, Escape, Trim};
+    use crate::core::parse::{parse_records, Writer};
+    use crate::core::ragged::RaggedPolicy;
+    use crate::core::transform::{Pipeline, Set};
+    use crate::core::filter::{Filter, Op, Where};
+    use crate::core::write::transform_stream;
+
+    fn pipeline() -> Pipeline {
+        Pipeline::new()
+            .then(Where::new(vec![Filter::new("b", Op::Ne, "skip").unwrap()]))
+            .then(Set::new("a", "x"))
+    }
+
+    /// Runs `data` through both `transform_stream` and small chunks.
+    fn both(data: &str, dialect: Dialect, ragged: RaggedPolicy) -> (String, String) {
+        let reader = || parse_records(data).with_dialect(dialect.clone()).with_ragged(ragged);
+        let mut serial = Writer::new(Vec::new());
+        let summary = transform_stream(reader(), &mut serial, &mut pipeline()).unwrap();
+        let mut parallel = Writer::new(Vec::new());
+        let chunked = transform_chunks(reader(), &mut parallel, &mut pipeline(), 3, 7).unwrap();
+        assert_eq!(chunked, summary);
+        let output = |writer: Writer<Vec<u8>>| String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        (output(serial), output(parallel))
+    }
+
+    #[test]
+    fn test_same_as_serial() {
+        let mut data = String::from("a,b,c\n");
+        for i in 0..200 {
+            data.push_str(&format!("{},\"multi\nline {}\",\"q\"\"uote\"\n\n", i, i));
+            data.push_str(&format!("{},skip,\"\n\"\r\n", i));
+            data.push_str(&format!("{}, b\"{},c\n", i, i));
+        }
+        let (serial, parallel) = both(&data, Dialect::default(), RaggedPolicy::Fail);
+        assert_eq!(serial.lines().count(), 1 + 200 * 3);
+        assert_eq!(parallel, serial);
+
+        let escaped = data.replace("\"\"", "\\\"");
+        let dialect = Dialect { escape: Escape::Char('\\'), trim: Trim::Fields, ..Dialect::default() };
+        let (serial, parallel) = both(&escaped, dialect, RaggedPolicy::Fail);
+        assert_eq!(parallel, serial);
+
+        let ragged = "a,b\n1,2\n3\n4,5,6\n7,8\n9\n";
+        let (serial, parallel) = both(ragged, Dialect::default(), RaggedPolicy::Skip);
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    fn test_errors_in_order() {
+        let data = "a,b\n1,2\n3,4\n\"5\n\",6,7\n8\n";
+        let mut writer = Writer::new(Vec::new());
+        let e = transform_chunks(parse_records(data), &mut writer, &mut pipeline(), 4, 4).unwrap_err();
+        assert_eq!(e.to_string(), "<input>:4: record has 3 fields, expected 2\n    \"5\n\",6,7");
+
+        let mut writer = Writer::new(Vec::new());
+        let reader = parse_records("1,x\n2,y\n").no_header();
+        transform_chunks(reader, &mut writer, &mut Set::new("#1", "z"), 2, 1).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner().unwrap()).unwrap(), "1,z\n2,z\n");
+    }
+
+    #[test]
+    fn test_scanner_resumes() {
+        // Grown a byte at a time, each byte scanned once.
+        let data = "a,\"x\n\"\"\ny\"\nb,\"é\n\"\nc".as_bytes();
+        for dialect in &[Dialect::default(), Dialect { trim: Trim::All, ..Dialect::default() }] {
+            let mut scanner = Scanner::default();
+            let ends: Vec<_> = (1..=data.len()).map(|len| scanner.record_end(&data[..len], dialect)).collect();
+            assert_eq!(scanner.offset, data.len());
+            assert_eq!(ends.iter().position(Option::is_some), Some(10));
+            assert_eq!(ends[data.len() - 1], Some(19));
+            scanner.consume(19);
+            assert_eq!((scanner.offset, scanner.record_end(&data[19..], dialect)), (1, None));
+        }
+    }
+}
@@ -0,0 +1,614 @@
+use super::Error;
+use super::compress::Finish;
+use crate::err::Position;
+use super::dialect::{Dialect, Escape, Terminator, Trim};
+use super::ragged::{RaggedPolicy, RaggedSummary};
+use std::{
+    io::{self, BufRead, BufWriter, Write},
+    mem,
+};
+
+/// Streaming reader over the records of RFC 4180 CSV input.
+///
+/// Quoted fields may contain commas, line breaks and doubled `""` quotes,
+/// records may end with `\n` or `\r\n`, and blank lines are skipped.
+/// Only one physical line is buffered at a time, so memory use does not
+/// grow with the size of the input.
+///
+/// By default the first record is the header. Input without one can be
+/// read with `no_header`, which names the columns `#0`, `#1`, ... after
+/// the width of the first record, or with `with_headers`. Once the header
+/// is known, records of another width are handled by the `RaggedPolicy`,
+/// failing by default.
+pub struct Reader<R> {
+    input: R,
+    line: String,
+    dialect: Dialect,
+    header: Header,
+    headers: Option<Vec<String>>,
+    pending: Option<Vec<String>>,
+    records: u64,
+    name: Option<String>,
+    line_number: u64,
+    record_line: u64,
+    raw: String,
+    ragged: RaggedPolicy,
+    summary: RaggedSummary,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Header {
+    FirstRow,
+    Missing,
+    Given,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    StartField,
+    Unquoted,
+    Quoted,
+    Escaped,
+    QuoteInQuoted,
+    Closed,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(input: R) -> Self {
+        Reader {
+            input,
+            line: String::new(),
+            dialect: Dialect::default(),
+            header: Header::FirstRow,
+            headers: None,
+            pending: None,
+            records: 0,
+            name: None,
+            line_number: 0,
+            record_line: 0,
+            raw: String::new(),
+            ragged: RaggedPolicy::default(),
+            summary: RaggedSummary::default(),
+        }
+    }
+
+    pub fn with_ragged(mut self, ragged: RaggedPolicy) -> Self {
+        self.ragged = ragged;
+        self
+    }
+
+    /// The ragged records met so far and what was done with them.
+    pub fn ragged_summary(&self) -> &RaggedSummary {
+        &self.summary
+    }
+
+    /// Names the input, usually its path, for error positions.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Where the last record read starts, along with its text.
+    pub fn record_position(&self) -> Position {
+        Position {
+            file: self.name.clone(),
+            line: self.record_line,
+            column: None,
+            record: Some(self.raw.trim_end_matches(['\r', '\n']).to_string()),
+        }
+    }
+
+    fn syntax(&self, message: &'static str, line: u64, column: usize) -> Error {
+        let position = Position { line, column: Some(column), ..self.record_position() };
+        Error::Syntax(message).at(position)
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Guesses the dialect from the start of the input, keeping the trim
+    /// policy already set.
+    pub fn sniff(mut self) -> Result<Self, Error> {
+        let sample = String::from_utf8_lossy(self.input.fill_buf()?).into_owned();
+        let trim = self.dialect.trim;
+        self.dialect = Dialect { trim, ..Dialect::sniff(&sample) };
+        Ok(self)
+    }
+
+    pub fn dialect(&self) -> &Dialect {
+        &self.dialect
+    }
+
+    /// Treats the first record as data rather than as the header.
+    pub fn no_header(mut self) -> Self {
+        self.header = Header::Missing;
+        self
+    }
+
+    /// Treats the first record as data and names the columns `headers`.
+    pub fn with_headers(mut self, headers: Vec<String>) -> Self {
+        self.header = Header::Given;
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Whether the column names were made up because the input has none.
+    pub fn synthetic_headers(&self) -> bool {
+        self.header == Header::Missing
+    }
+
+    /// Takes the input back once the header is read, along with the first
+    /// record when it was read ahead to size a missing header, and the
+    /// number of lines read.
+    pub(crate) fn into_rest(self) -> (R, Option<Vec<String>>, u64) {
+        (self.input, self.pending, self.line_number)
+    }
+
+    /// Reads `chunk`, whole records of the same input starting at `line`,
+    /// the way this reader would, once its header is known.
+    pub(crate) fn chunk<'a>(&self, chunk: &'a [u8], line: u64) -> Reader<&'a [u8]> {
+        Reader {
+            dialect: self.dialect.clone(),
+            header: Header::Given,
+            headers: self.headers.clone(),
+            name: self.name.clone(),
+            line_number: line - 1,
+            ragged: self.ragged,
+            ..Reader::new(chunk)
+        }
+    }
+
+    /// The column names, read from the input on the first call when the
+    /// first record is the header.
+    pub fn headers(&mut self) -> Result<Vec<String>, Error> {
+        if let Some(headers) = &self.headers {
+            return Ok(headers.clone());
+        }
+        let first = match self.read_record()? {
+            Some(record) => record,
+            None => Err("input file missing")?
+        };
+        let headers = match self.header {
+            Header::FirstRow => first,
+            _ => {
+                let headers = (0..first.len()).map(|i| format!("#{}", i)).collect();
+                self.pending = Some(first);
+                headers
+            }
+        };
+        self.headers = Some(headers.clone());
+        Ok(headers)
+    }
+
+    fn read_line(&mut self) -> Result<bool, Error> {
+        let end = match self.dialect.terminator {
+            Terminator::Cr => b'\r',
+            _ => b'\n',
+        };
+        let mut bytes = mem::take(&mut self.line).into_bytes();
+        bytes.clear();
+        self.line_number += 1;
+        let read = self.input.read_until(end, &mut bytes).map_err(|e| {
+            Error::from(e).at(Position { line: self.line_number, record: None, ..self.record_position() })
+        })?;
+        self.line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(_) => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+                let position = Position { line: self.line_number, record: None, ..self.record_position() };
+                return Err(Error::from(e).at(position));
+            }
+        };
+        Ok(read > 0)
+    }
+
+    fn trims(&self, header: bool) -> bool {
+        match self.dialect.trim {
+            Trim::None => false,
+            Trim::Headers => header,
+            Trim::Fields => !header,
+            Trim::All => true,
+        }
+    }
+
+    /// Reads the next record, or `None` at the end of the input.
+    pub fn read_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        loop {
+            let record = match self.parse_record()? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            let width = match &self.headers {
+                Some(headers) => headers.len(),
+                None => return Ok(Some(record)),
+            };
+            if let Some(record) = self.fit(record, width)? {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    fn fit(&mut self, mut record: Vec<String>, width: usize) -> Result<Option<Vec<String>>, Error> {
+        let found = record.len();
+        if found == width {
+            return Ok(Some(record));
+        }
+        match (self.ragged, found < width) {
+            (RaggedPolicy::Skip, _) => {
+                self.summary.skip(self.record_line);
+                return Ok(None);
+            }
+            (RaggedPolicy::Pad, true) | (RaggedPolicy::Fit, true) => {
+                record.resize(width, String::new());
+                self.summary.padded += 1;
+            }
+            (RaggedPolicy::Truncate, false) | (RaggedPolicy::Fit, false) => {
+                record.truncate(width);
+                self.summary.truncated += 1;
+            }
+            _ => {
+                let e = Error::Ragged { expected: width, found };
+                return Err(e.at(self.record_position()));
+            }
+        }
+        Ok(Some(record))
+    }
+
+    fn parse_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        loop {
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            if !split_terminator(&self.line).0.is_empty() {
+                break;
+            }
+        }
+        let trim = self.trims(self.header == Header::FirstRow && self.records == 0);
+        self.records += 1;
+        self.record_line = self.line_number;
+        self.raw.clear();
+        let mut opened = (0, 0);
+        let Dialect { delimiter, quote, escape, .. } = self.dialect;
+        let escape = match escape {
+            Escape::Char(c) => Some(c),
+            Escape::Doubled => None,
+        };
+        let mut record = Vec::new();
+        let mut field = String::new();
+        let mut state = State::StartField;
+        loop {
+            let (content, terminator) = split_terminator(&self.line);
+            self.raw.push_str(content);
+            for (i, c) in content.chars().enumerate() {
+                state = match state {
+                    State::StartField if c == quote => {
+                        opened = (self.line_number, i + 1);
+                        State::Quoted
+                    }
+                    State::StartField if trim && c.is_whitespace() => State::StartField,
+                    State::StartField | State::Unquoted if c == delimiter => {
+                        end_field(&mut record, &mut field, trim);
+                        State::StartField
+                    }
+                    State::StartField | State::Unquoted => {
+                        field.push(c);
+                        State::Unquoted
+                    }
+                    State::Quoted if Some(c) == escape => State::Escaped,
+                    State::Quoted if c == quote && escape.is_some() => State::Closed,
+                    State::Quoted if c == quote => State::QuoteInQuoted,
+                    State::QuoteInQuoted if c == quote => {
+                        field.push(c);
+                        State::Quoted
+                    }
+                    State::Quoted | State::Escaped => {
+                        field.push(c);
+                        State::Quoted
+                    }
+                    State::QuoteInQuoted | State::Closed if c == delimiter => {
+                        record.push(mem::take(&mut field));
+                        State::StartField
+                    }
+                    State::QuoteInQuoted | State::Closed if trim && c.is_whitespace() => {
+                        State::Closed
+                    }
+                    State::QuoteInQuoted | State::Closed => {
+                        let message = "unexpected character after closing quote";
+                        return Err(self.syntax(message, self.line_number, i + 1));
+                    }
+                };
+            }
+            match state {
+                State::Quoted | State::Escaped => {
+                    // The line break belongs to the quoted field, keep it verbatim.
+                    field.push_str(terminator);
+                    self.raw.push_str(terminator);
+                    if terminator.is_empty() || !self.read_line()? {
+                        return Err(self.syntax("unterminated quoted field", opened.0, opened.1));
+                    }
+                    state = State::Quoted;
+                }
+                State::StartField | State::Unquoted => {
+                    end_field(&mut record, &mut field, trim);
+                    return Ok(Some(record));
+                }
+                State::QuoteInQuoted | State::Closed => {
+                    record.push(field);
+                    return Ok(Some(record));
+                }
+            }
+        }
+    }
+}
+
+fn end_field(record: &mut Vec<String>, field: &mut String, trim: bool) {
+    let mut field = mem::take(field);
+    if trim {
+        field.truncate(field.trim_end().len());
+    }
+    record.push(field);
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Vec<String>, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// A stream of records under a header, as read by `transform_stream`.
+pub trait Source {
+    fn headers(&mut self) -> Result<Vec<String>, Error>;
+    /// Reads the next record, or `None` at the end of the input.
+    fn read_record(&mut self) -> Result<Option<Vec<String>>, Error>;
+    /// Where the record last read comes from.
+    fn record_position(&self) -> Position;
+    fn name(&self) -> Option<&str>;
+    /// Whether the header was made up rather than read.
+    fn synthetic_headers(&self) -> bool {
+        false
+    }
+    fn ragged_summary(&self) -> RaggedSummary {
+        RaggedSummary::default()
+    }
+}
+
+impl<R: BufRead> Source for Reader<R> {
+    fn headers(&mut self) -> Result<Vec<String>, Error> {
+        Reader::headers(self)
+    }
+    fn read_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        Reader::read_record(self)
+    }
+    fn record_position(&self) -> Position {
+        Reader::record_position(self)
+    }
+    fn name(&self) -> Option<&str> {
+        Reader::name(self)
+    }
+    fn synthetic_headers(&self) -> bool {
+        Reader::synthetic_headers(self)
+    }
+    fn ragged_summary(&self) -> RaggedSummary {
+        Reader::ragged_summary(self).clone()
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn headers(&mut self) -> Result<Vec<String>, Error> {
+        (**self).headers()
+    }
+    fn read_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        (**self).read_record()
+    }
+    fn record_position(&self) -> Position {
+        (**self).record_position()
+    }
+    fn name(&self) -> Option<&str> {
+        (**self).name()
+    }
+    fn synthetic_headers(&self) -> bool {
+        (**self).synthetic_headers()
+    }
+    fn ragged_summary(&self) -> RaggedSummary {
+        (**self).ragged_summary()
+    }
+}
+
+fn split_terminator(line: &str) -> (&str, &str) {
+    let content = match line.strip_suffix('\n') {
+        Some(content) => content.strip_suffix('\r').unwrap_or(content),
+        None => line.strip_suffix('\r').unwrap_or(line),
+    };
+    line.split_at(content.len())
+}
+
+/// # Usage:
+/// ```
+/// use csv_challenge::parse_records;
+/// let data = "a,b\n\"x, y\",\"say \"\"hi\"\"\"\n";
+/// let records: Vec<Vec<String>> = parse_records(data)
+///     .collect::<Result<_, _>>().unwrap();
+/// assert_eq!(records[1], vec!["x, y", "say \"hi\""]);
+/// ```
+pub fn parse_records(data: &str) -> Reader<&[u8]> {
+    Reader::new(data.as_bytes())
+}
+
+/// Buffered CSV writer, flushing to the underlying output as its
+/// buffer fills up.
+pub struct Writer<W: Write> {
+    output: BufWriter<W>,
+    buffer: String,
+    dialect: Dialect,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W) -> Self {
+        Writer {
+            output: BufWriter::new(output),
+            buffer: String::new(),
+            dialect: Dialect::default(),
+        }
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn write_record<S: AsRef<str>>(&mut self, fields: &[S]) -> Result<(), Error> {
+        self.buffer.clear();
+        self.dialect.format_record(&mut self.buffer, fields);
+        self.output.write_all(self.buffer.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    /// Flushes the buffer and ends the underlying output, writing out the
+    /// end of a compressed stream.
+    pub fn finish(&mut self) -> Result<(), Error>
+    where
+        W: Finish,
+    {
+        self.output.flush()?;
+        self.output.get_mut().finish()?;
+        Ok(())
+    }
+
+    /// Flushes the buffer and hands back the underlying output.
+    pub fn into_inner(self) -> Result<W, Error> {
+        self.output.into_inner().map_err(|e| e.into_error().into())
+    }
+}
+
+/// Appends one record to `out` in the default dialect, quoting the
+/// fields that need it, followed by a `\n` line terminator.
+pub fn format_record<S: AsRef<str>>(out: &mut String, fields: &[S]) {
+    Dialect::default().format_record(out, fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{format_record, parse_records, Writer};
+    use crate::core::ragged::RaggedPolicy;
+
+    fn parse(data: &str) -> Vec<Vec<String>> {
+        parse_records(data).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_parse_quoted_fields() {
+        let records = parse("a,b,c\r\n\"x,y\",\"he said \"\"no\"\"\",\"line1\nline2\"\r\n");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], vec!["x,y", "he said \"no\"", "line1\nline2"]);
+    }
+
+    #[test]
+    fn test_parse_empty_fields_and_blank_lines() {
+        let records = parse("a,,\"\"\n\n1,2,3");
+        assert_eq!(records, vec![vec!["a", "", ""], vec!["1", "2", "3"]]);
+    }
+
+    #[test]
+    fn test_invalid_quoting() {
+        assert!(parse_records("\"abc").next().unwrap().is_err());
+        assert!(parse_records("\"abc\"d,e").next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let data = "a,b\n1,2\n\n\"x\"y,3\n4,\"open\nstill open\n";
+        let mut reader = parse_records(data).with_name("bad.csv");
+        assert!(reader.headers().is_ok());
+        assert!(reader.next().unwrap().is_ok());
+        let e = reader.next().unwrap().unwrap_err();
+        assert_eq!(e.to_string(), "bad.csv:4:4: unexpected character after closing quote\n    \"x\"y,3");
+        let e = reader.next().unwrap().unwrap_err();
+        let position = e.position().unwrap();
+        assert_eq!((position.line, position.column), (5, Some(3)));
+        assert_eq!(position.record.as_deref(), Some("4,\"open\nstill open"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = "name,quote\n\"Doe, John\",\"\"\"hi\"\"\"\nplain,\"two\nlines\"\n";
+        let records = parse(data);
+        let mut out = String::new();
+        for record in &records {
+            format_record(&mut out, record);
+        }
+        assert_eq!(out, data);
+        assert_eq!(parse(&out), records);
+
+        let data = "name\n\"\"\nJohn\n";
+        let records = parse(data);
+        let mut out = String::new();
+        for record in &records {
+            format_record(&mut out, record);
+        }
+        assert_eq!((records.len(), out.as_str()), (3, data));
+    }
+
+    #[test]
+    fn test_header_modes() {
+        let data = "1,2\n3,4\n";
+        let mut reader = parse_records(data);
+        assert_eq!(reader.headers().unwrap(), vec!["1", "2"]);
+        assert_eq!(reader.count(), 1);
+
+        let mut reader = parse_records(data).no_header();
+        assert_eq!(reader.headers().unwrap(), vec!["#0", "#1"]);
+        assert!(reader.synthetic_headers());
+        assert_eq!(reader.count(), 2);
+
+        let headers = vec!["a".to_string(), "b".to_string()];
+        let mut reader = parse_records(data).with_headers(headers.clone());
+        assert_eq!(reader.headers().unwrap(), headers);
+        assert_eq!(reader.count(), 2);
+    }
+
+    #[test]
+    fn test_ragged_policies() {
+        let data = "a,b\n1\n2,3\n4,5,6\n";
+        let read = |ragged| {
+            let mut reader = parse_records(data).with_ragged(ragged);
+            reader.headers().unwrap();
+            let records: Result<Vec<_>, _> = reader.by_ref().collect();
+            (records, reader.ragged_summary().clone())
+        };
+        assert!(read(RaggedPolicy::Fail).0.is_err());
+        assert!(read(RaggedPolicy::Pad).0.is_err());
+        let (records, summary) = read(RaggedPolicy::Skip);
+        assert_eq!(records.unwrap(), vec![vec!["2", "3"]]);
+        assert_eq!((summary.skipped, summary.skipped_lines), (2, vec![2, 4]));
+        let (records, summary) = read(RaggedPolicy::Fit);
+        assert_eq!(records.unwrap(), vec![vec!["1", ""], vec!["2", "3"], vec!["4", "5"]]);
+        assert_eq!((summary.padded, summary.truncated), (1, 1));
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_record(&["a", "b,c"]).unwrap();
+        writer.write_record(&["1", ""]).unwrap();
+        let output = writer.into_inner().unwrap();
+        assert_eq!(output, b"a,\"b,c\"\n1,\n");
+    }
+}
@@ -0,0 +1,402 @@
+use super::{Error, PathBuf};
+use super::expr::Compute;
+use super::filter::{Filter, When, Where};
+use super::format::{Format, Output};
+use super::parse::Source;
+use super::ragged::RaggedSummary;
+use super::replace::Replacement;
+use super::sort::{Sort, SortKey, DEFAULT_MEMORY_LIMIT};
+use super::transform::{Exclude, Pipeline, Rename, Reorder, Select, Set, Transform};
+use super::write::transform_stream;
+use crate::err::Position;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use std::{env, fmt, fs};
+
+/// The pipeline file as written, see `Plan`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlanFile {
+    format: Option<String>,
+    #[serde(default, rename = "step")]
+    steps: Vec<StepFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepFile {
+    filter: Option<Vec<String>>,
+    set: Option<Pairs>,
+    replace: Option<ReplaceFile>,
+    map: Option<Pairs>,
+    template: Option<Pairs>,
+    add: Option<Pairs>,
+    when: Option<Vec<String>>,
+    rename: Option<Pairs>,
+    drop: Option<Vec<String>>,
+    select: Option<Vec<String>>,
+    order: Option<Vec<String>>,
+    sort: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplaceFile {
+    column: String,
+    pattern: String,
+    with: String,
+}
+
+/// A table of strings, in the order written.
+struct Pairs(Vec<(String, String)>);
+
+impl<'de> Deserialize<'de> for Pairs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PairsVisitor;
+        impl<'de> Visitor<'de> for PairsVisitor {
+            type Value = Pairs;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a table of strings")
+            }
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Pairs, M::Error> {
+                let mut pairs = Vec::new();
+                while let Some(pair) = map.next_entry()? {
+                    pairs.push(pair);
+                }
+                Ok(Pairs(pairs))
+            }
+        }
+        deserializer.deserialize_map(PairsVisitor)
+    }
+}
+
+impl StepFile {
+    /// The step as a transform, `None` for a sort.
+    fn build(self) -> Result<Option<Pipeline>, Error> {
+        let mut actions = Vec::new();
+        let mut sets = Pipeline::new();
+        let mut other = Pipeline::new();
+        if let Some(filters) = &self.filter {
+            actions.push("filter");
+            other.push(Where::new(parse_filters(filters)?));
+        }
+        if let Some(Pairs(pairs)) = &self.set {
+            actions.push("set");
+            for (column, value) in pairs {
+                sets.push(Set::new(column, value));
+            }
+        }
+        if let Some(replace) = &self.replace {
+            actions.push("replace");
+            sets.push(Set::with(&replace.column, Replacement::regex(&replace.pattern, &replace.with)?));
+        }
+        if let Some(Pairs(pairs)) = &self.map {
+            actions.push("map");
+            for (column, file) in pairs {
+                sets.push(Set::with(column, Replacement::map_file(PathBuf::from(file))?));
+            }
+        }
+        if let Some(Pairs(pairs)) = &self.template {
+            actions.push("template");
+            for (column, template) in pairs {
+                sets.push(Set::with(column, Replacement::template(template)?));
+            }
+        }
+        if let Some(Pairs(pairs)) = &self.add {
+            actions.push("add");
+            for (column, expr) in pairs {
+                other.push(Compute::new(column, expr)?);
+            }
+        }
+        if let Some(Pairs(pairs)) = &self.rename {
+            actions.push("rename");
+            for (from, to) in pairs {
+                other.push(Rename::new(from, to));
+            }
+        }
+        if let Some(columns) = &self.drop {
+            actions.push("drop");
+            other.push(Exclude::new(columns));
+        }
+        if let Some(columns) = &self.select {
+            actions.push("select");
+            other.push(Select::new(columns));
+        }
+        if let Some(columns) = &self.order {
+            actions.push("order");
+            other.push(Reorder::new(columns));
+        }
+        if self.sort.is_some() {
+            actions.push("sort");
+        }
+        match actions[..] {
+            [] => Err("no action, expected one of filter, set, replace, map, template, add, rename, drop, \
+                       select, order, sort")?,
+            [_] => {}
+            _ => return Err(Error::Data(format!("more than one action: {}", actions.join(", ")))),
+        }
+        match (&self.when, sets.is_empty()) {
+            (Some(filters), false) => Ok(Some(Pipeline::new().then(When::new(parse_filters(filters)?, sets)))),
+            (Some(_), true) => Err("`when` only applies to set, replace, map and template")?,
+            (None, false) => Ok(Some(sets)),
+            (None, true) if self.sort.is_some() => Ok(None),
+            (None, true) => Ok(Some(other)),
+        }
+    }
+}
+
+fn parse_filters(exprs: &[String]) -> Result<Vec<Filter>, Error> {
+    exprs.iter().map(|expr| Filter::parse(expr)).collect()
+}
+
+/// Steps run over the records of `source` as they are read.
+struct Steps<'a, S> {
+    source: &'a mut S,
+    steps: &'a mut Pipeline,
+}
+
+impl<S: Source> Source for Steps<'_, S> {
+    fn headers(&mut self) -> Result<Vec<String>, Error> {
+        let headers = self.source.headers()?;
+        self.steps.headers(&headers).map_err(|e| {
+            e.at(Position { file: self.source.name().map(String::from), ..Position::default() })
+        })
+    }
+    fn read_record(&mut self) -> Result<Option<Vec<String>>, Error> {
+        while let Some(record) = self.source.read_record()? {
+            match self.steps.apply(record) {
+                Ok(Some(record)) => return Ok(Some(record)),
+                Ok(None) => {}
+                Err(e) => return Err(e.at(self.source.record_position())),
+            }
+        }
+        Ok(None)
+    }
+    fn record_position(&self) -> Position {
+        self.source.record_position()
+    }
+    fn name(&self) -> Option<&str> {
+        self.source.name()
+    }
+    fn synthetic_headers(&self) -> bool {
+        self.source.synthetic_headers()
+    }
+}
+
+/// Steps run over the records on their way to `output`.
+struct Transformed<'a, O: ?Sized> {
+    output: &'a mut O,
+    steps: &'a mut Pipeline,
+}
+
+impl<O: Output + ?Sized> Output for Transformed<'_, O> {
+    fn write_headers(&mut self, headers: &[String], synthetic: bool) -> Result<(), Error> {
+        let headers = self.steps.headers(headers)?;
+        self.output.write_headers(&headers, synthetic)
+    }
+
+    fn write_record(&mut self, record: &[String]) -> Result<(), Error> {
+        match self.steps.apply(record.to_vec())? {
+            Some(record) => self.output.write_record(&record),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Error> {
+        self.output.finish()
+    }
+}
+
+/// A sequence of steps read from a TOML pipeline file, in place of a long
+/// command line:
+///
+/// ```toml
+/// # Output format, csv if not present.
+/// format = "markdown"
+///
+/// [[step]]
+/// filter = ["Age>=30"]                 # keeps records matching every filter
+///
+/// [[step]]
+/// set = { City = "Beijing" }
+/// when = ["Species=Human"]             # only on matching records
+///
+/// [[step]]
+/// replace = { column = "Eyes color", pattern = "^(.)", with = "[$1]" }
+///
+/// [[step]]
+/// add = { Label = "upper({First Name}) + ' ' + {Last Name}" }
+///
+/// [[step]]
+/// sort = ["Age:num:desc", "City"]
+///
+/// [[step]]
+/// select = ["Label", "Age", "City"]
+/// ```
+///
+/// Each step takes one of `filter`, `set`, `replace`, `map`, `template`,
+/// `add`, `rename`, `drop`, `select`, `order` and `sort`, written as on
+/// the command line. `when` restricts `set`, `replace`, `map` and
+/// `template`. There can be one `sort`, which runs over the output of the
+/// steps before it.
+///
+/// The file is checked when parsed, and every column name against the
+/// header before any record is read.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Plan, Writer};
+/// let mut plan = Plan::parse(r#"
+///     [[step]]
+///     filter = ["Age>=18"]
+///     [[step]]
+///     sort = ["Age:num"]
+///     [[step]]
+///     rename = { Name = "Adult" }
+/// "#).unwrap();
+/// let mut writer = Writer::new(Vec::new());
+/// plan.run(parse_records("Name,Age\nJohn,32\nFlip,12\nBrad,20\n"), &mut writer).unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "Adult,Age\nBrad,20\nJohn,32\n");
+/// ```
+pub struct Plan {
+    format: Option<Format>,
+    before: Pipeline,
+    sort: Option<Vec<SortKey>>,
+    after: Pipeline,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+}
+
+impl Plan {
+    pub fn parse(text: &str) -> Result<Plan, Error> {
+        let file: PlanFile = toml::from_str(text).map_err(|e| Error::Data(format!("invalid pipeline: {}", e)))?;
+        let format = match &file.format {
+            Some(format) => Some(format.parse::<Format>().map_err(|e| Error::Data(format!("invalid pipeline: {}", e)))?),
+            None => None,
+        };
+        let mut plan = Plan {
+            format,
+            before: Pipeline::new(),
+            sort: None,
+            after: Pipeline::new(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            temp_dir: env::temp_dir(),
+        };
+        for (i, step) in file.steps.into_iter().enumerate() {
+            let invalid = |e: Error| Error::Data(format!("invalid pipeline: step {}: {}", i + 1, e));
+            let sort = match &step.sort {
+                Some(keys) => Some(keys.iter().map(|key| SortKey::parse(key)).collect::<Result<Vec<_>, _>>().map_err(invalid)?),
+                None => None,
+            };
+            let steps = step.build().map_err(invalid)?;
+            match (steps, sort) {
+                (Some(steps), _) if plan.sort.is_some() => plan.after.push(steps),
+                (Some(steps), _) => plan.before.push(steps),
+                (None, Some(_)) if plan.sort.is_some() => return Err(invalid(Error::from("only one sort is allowed"))),
+                (None, sort) => plan.sort = sort,
+            }
+        }
+        Ok(plan)
+    }
+
+    pub fn load(path: PathBuf) -> Result<Plan, Error> {
+        let text = fs::read_to_string(&path)?;
+        let position = Position { file: Some(path.to_string_lossy().into_owned()), ..Position::default() };
+        Plan::parse(&text).map_err(|e| e.at(position))
+    }
+
+    /// Bytes of records a sort keeps in memory before spilling to disk.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Where a sort spills, the system temporary directory by default.
+    pub fn temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    /// The output format the file asks for.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Runs the steps over `reader` into `output`, returning what the
+    /// reader's `RaggedPolicy` did.
+    pub fn run<S: Source, O: Output + ?Sized>(&mut self, mut reader: S, output: &mut O) -> Result<RaggedSummary, Error> {
+        let keys = match &self.sort {
+            Some(keys) => keys.clone(),
+            None => return transform_stream(reader, output, &mut self.before),
+        };
+        let source = Steps { source: &mut reader, steps: &mut self.before };
+        let mut output = Transformed { output, steps: &mut self.after };
+        Sort::new(keys)
+            .memory_limit(self.memory_limit)
+            .temp_dir(self.temp_dir.clone())
+            .sort(source, &mut output)?;
+        Ok(reader.ragged_summary())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Plan;
+    use crate::core::parse::{parse_records, Writer};
+    use crate::core::read::open_csv;
+    use std::path::PathBuf;
+
+    fn run(plan: &str, data: &str) -> Result<String, crate::err::Error> {
+        let mut writer = Writer::new(Vec::new());
+        Plan::parse(plan)?.run(parse_records(data), &mut writer)?;
+        Ok(String::from_utf8(writer.into_inner().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_steps_in_order() {
+        let plan = r#"
+            [[step]]
+            add = { Total = "Price * Qty", Big = "Total > 10" }
+            [[step]]
+            set = { Name = "?" }
+            when = ["Qty=0"]
+            [[step]]
+            replace = { column = "Name", pattern = "^(.)", with = "<$1>" }
+            [[step]]
+            sort = ["Total:num:desc"]
+            [[step]]
+            drop = ["Price", "Qty"]
+            [[step]]
+            filter = ["Big=false"]
+        "#;
+        let data = "Name,Price,Qty\npen,1.5,4\nbook,12,1\nink,3,0\n";
+        assert_eq!(run(plan, data).unwrap(), "Name,Total,Big\n<p>en,6,false\n<?>,0,false\n");
+
+        let plan = Plan::load(PathBuf::from("./input/challenge.pipeline.toml")).unwrap();
+        assert_eq!(plan.format(), Some(crate::core::format::Format::Markdown));
+        let mut writer = Writer::new(Vec::new());
+        let mut plan = plan;
+        plan.run(open_csv(PathBuf::from("./input/challenge.csv")).unwrap(), &mut writer).unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output.lines().next(), Some("Name,Age,City"));
+    }
+
+    #[test]
+    fn test_invalid_plans() {
+        let invalid = |plan: &str| Plan::parse(plan).err().map(|e| e.to_string()).unwrap_or_default();
+        assert!(invalid("[[step]]\nselct = [\"A\"]\n").contains("unknown field `selct`"));
+        assert!(invalid("[[step]]\nselect = [\"A\"]\ndrop = [\"B\"]\n").contains("step 1: more than one action: drop, select"));
+        assert!(invalid("[[step]]\nfilter = [\"A\"]\n").contains("step 1:"));
+        assert!(invalid("[[step]]\nsort = [\"A\"]\n[[step]]\nsort = [\"B\"]\n").contains("step 2: only one sort"));
+        assert!(invalid("[[step]]\ndrop = [\"A\"]\nwhen = [\"B=1\"]\n").contains("`when` only applies"));
+        assert!(invalid("[[step]]\nadd = { A = \"1 +\" }\n").contains("invalid expression"));
+        assert!(invalid("format = \"xml\"\n").contains("format must be one of"));
+
+        // Columns are checked against the header before any record.
+        let e = run("[[step]]\nsort = [\"Name\"]\n[[step]]\nselect = [\"Nope\"]\n", "Name\nb\na\n").unwrap_err();
+        assert!(e.to_string().contains("Nope"));
+        let e = run("[[step]]\nrename = { Name = \"Who\" }\n[[step]]\nsort = [\"Name\"]\n", "Name\nb\n").unwrap_err();
+        assert!(e.to_string().contains("Name"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{fmt, str::FromStr};
+
+/// What to do with a record whose field count differs from the header's.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RaggedPolicy {
+    /// Stop with a positioned `Error::Ragged`.
+    #[default]
+    Fail,
+    /// Leave the record out and note its line in the summary.
+    Skip,
+    /// Fill missing fields with empty values, fail on extra ones.
+    Pad,
+    /// Drop extra fields, fail on missing ones.
+    Truncate,
+    /// Pad short records and truncate long ones.
+    Fit,
+}
+
+impl FromStr for RaggedPolicy {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(RaggedPolicy::Fail),
+            "skip" => Ok(RaggedPolicy::Skip),
+            "pad" => Ok(RaggedPolicy::Pad),
+            "truncate" => Ok(RaggedPolicy::Truncate),
+            "fit" => Ok(RaggedPolicy::Fit),
+            _ => Err("ragged policy must be one of fail, skip, pad, truncate, fit"),
+        }
+    }
+}
+
+/// How many lines of skipped records a `RaggedSummary` keeps.
+const SKIPPED_LINES: usize = 10;
+
+/// The ragged records a `RaggedPolicy` dealt with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RaggedSummary {
+    pub skipped: u64,
+    /// Lines where the first skipped records start, at most ten.
+    pub skipped_lines: Vec<u64>,
+    pub padded: u64,
+    pub truncated: u64,
+}
+
+impl RaggedSummary {
+    pub fn is_empty(&self) -> bool {
+        self.skipped == 0 && self.padded == 0 && self.truncated == 0
+    }
+
+    /// Counts a record skipped at `line`.
+    pub(crate) fn skip(&mut self, line: u64) {
+        self.skipped += 1;
+        if self.skipped_lines.len() < SKIPPED_LINES {
+            self.skipped_lines.push(line);
+        }
+    }
+
+    /// Adds the records of `other`, read after these.
+    pub(crate) fn append(&mut self, other: RaggedSummary) {
+        self.skipped += other.skipped;
+        let room = SKIPPED_LINES - self.skipped_lines.len();
+        self.skipped_lines.extend(other.skipped_lines.into_iter().take(room));
+        self.padded += other.padded;
+        self.truncated += other.truncated;
+    }
+}
+
+impl fmt::Display for RaggedSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ragged rows: {} skipped, {} padded, {} truncated",
+               self.skipped, self.padded, self.truncated)?;
+        if !self.skipped_lines.is_empty() {
+            let lines: Vec<String> = self.skipped_lines.iter().map(|l| l.to_string()).collect();
+            write!(f, " (skipped lines {}", lines.join(", "))?;
+            let more = self.skipped - self.skipped_lines.len() as u64;
+            if more > 0 {
+                write!(f, " … and {} more", more)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RaggedSummary;
+
+    #[test]
+    fn test_skipped_lines_bounded() {
+        let mut summary = RaggedSummary::default();
+        (1..=8).for_each(|line| summary.skip(line));
+        let mut later = RaggedSummary::default();
+        (100..105).for_each(|line| later.skip(line));
+        summary.append(later);
+        assert_eq!(summary.skipped, 13);
+        assert_eq!(summary.skipped_lines, vec![1, 2, 3, 4, 5, 6, 7, 8, 100, 101]);
+        assert_eq!(summary.to_string(), "ragged rows: 13 skipped, 0 padded, 0 truncated \
+                   (skipped lines 1, 2, 3, 4, 5, 6, 7, 8, 100, 101 … and 3 more)");
+    }
+}
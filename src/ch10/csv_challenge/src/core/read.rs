@@ -1,5 +1,20 @@
-use super::{Error, PathBuf, File, Read, Write};
+use super::{Error, PathBuf, File, Write};
+use super::parse::{Reader, Writer};
+use super::json::JsonReader;
+use super::dialect::Dialect;
+use super::format::{Format, Output};
+use super::encoding::Charset;
+use super::compress::{Compression, Finish};
+use crate::err::Position;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    process,
+};
 
+/// Reads `csv_file` whole, decompressed and decoded to UTF-8.
+///
 /// # Usage:
 /// ```ignore
 /// use std::path::PathBuf;
@@ -8,10 +23,19 @@ use super::{Error, PathBuf, File, Read, Write};
 /// assert!(csv_data.is_ok());
 /// ```
 pub fn load_csv(csv_file: PathBuf) -> Result<String, Error> {
-    let file = read(csv_file)?;
-    Ok(file)
+    let file = open(csv_file)?;
+    let (mut stream, _) = Charset::decode(Compression::decompress(BufReader::new(file))?, None)?;
+    let mut buffer = String::new();
+    stream.read_to_string(&mut buffer)?;
+    if buffer.is_empty() {
+        return Err("input file missing")?
+    }
+    Ok(buffer)
 }
 
+/// Writes `csv_data` to `filename` as is, compressed if its extension is
+/// `.gz` or `.zst`.
+///
 /// # Usage:
 /// ```ignore
 /// let filename = PathBuf::from("./files/challenge.csv");
@@ -22,32 +46,172 @@ pub fn load_csv(csv_file: PathBuf) -> Result<String, Error> {
 /// ```
 pub fn write_csv(csv_data: &str, filename: &str) -> Result<(), Error> 
 {
-   write(csv_data, filename)?;
+   let mut output = output_stream(Some(filename))?;
+   output.write_all(csv_data.as_bytes())?;
+   output.finish()?;
    Ok(())
 }
-fn read(path: PathBuf) -> Result<String, Error> {
-   let mut buffer = String::new();
-   let mut file = open(path)?;
-   file.read_to_string(&mut buffer)?;
-   if buffer.is_empty() {
-       return Err("input file missing")?
-   }
-   Ok(buffer)
+/// Opens `csv_file` for streaming, record by record, decompressing it
+/// and decoding it from the encoding sniffed from its start.
+pub fn open_csv(csv_file: PathBuf) -> Result<Reader<Box<dyn BufRead>>, Error> {
+    let name = csv_file.to_string_lossy().into_owned();
+    let file = open(csv_file)?;
+    let (stream, _) = Charset::decode(Compression::decompress(BufReader::new(file))?, None)?;
+    Ok(Reader::new(stream).with_name(&name))
+}
+
+/// Creates `filename` for streaming output, record by record, compressed
+/// if its extension is `.gz` or `.zst`.
+pub fn create_csv(filename: &str) -> Result<Writer<Box<dyn Finish>>, Error> {
+    let file = File::create(filename)?;
+    Ok(Writer::new(Box::new(Compression::from_path(filename).compress(file)?)))
 }
+
+/// Opens `input` for streaming, `-` standing for stdin.
+pub fn open_input(input: &str) -> Result<Reader<Box<dyn BufRead>>, Error> {
+    let (stream, name, _) = input_stream(input, None)?;
+    Ok(Reader::new(stream).with_name(name))
+}
+
+/// Opens `input` as JSON or NDJSON, `-` standing for stdin. A file is
+/// opened again to stream its records, see `JsonReader`.
+pub fn open_json(input: &str) -> Result<JsonReader<Box<dyn BufRead>>, Error> {
+    let (stream, name, charset) = input_stream(input, None)?;
+    let reader = JsonReader::new(stream).with_name(name);
+    if input == "-" {
+        return Ok(reader);
+    }
+    let input = input.to_string();
+    Ok(reader.reopen(move || Ok(input_stream(&input, Some(charset))?.0)))
+}
+
+/// Opens `input` as UTF-8 text, decompressed if it is gzip or zstd and
+/// decoded from `charset`, else from the one sniffed from its start,
+/// returning the name to report it by and the charset.
+pub fn input_stream(input: &str, charset: Option<Charset>)
+    -> Result<(Box<dyn BufRead>, &str, Charset), Error> {
+    let stream = if input == "-" {
+        Compression::decompress(io::stdin().lock())?
+    } else {
+        Compression::decompress(BufReader::new(open(PathBuf::from(input))?))?
+    };
+    let (stream, charset) = Charset::decode(stream, charset)?;
+    let name = if input == "-" { "<stdin>" } else { input };
+    Ok((stream, name, charset))
+}
+
+/// Creates `output` for streaming, stdout if not present or `-`.
+pub fn create_output(output: Option<&str>) -> Result<Writer<Box<dyn Finish>>, Error> {
+    Ok(Writer::new(output_stream(output)?))
+}
+
+/// Creates `output` like `create_output`, writing records in `format`.
+pub fn create_formatted(output: Option<&str>, format: Format, dialect: Dialect)
+    -> Result<Box<dyn Output>, Error> {
+    create_encoded(output, format, dialect, Charset::default())
+}
+
+/// Creates `output` like `create_formatted`, encoded in `charset`.
+pub fn create_encoded(output: Option<&str>, format: Format, dialect: Dialect, charset: Charset)
+    -> Result<Box<dyn Output>, Error> {
+    let stream = output_stream(output)?;
+    if charset == Charset::default() {
+        return Ok(format.writer(stream, dialect));
+    }
+    Ok(format.writer(charset.encoder(stream), dialect))
+}
+
+/// Creates `output` for plain text, stdout if not present or `-`, and
+/// compressed if its extension is `.gz` or `.zst`. It is complete once
+/// `finish` returns.
+pub fn output_stream(output: Option<&str>) -> Result<Box<dyn Finish>, Error> {
+    let output: Box<dyn Finish> = match output {
+        None | Some("-") => Box::new(io::stdout().lock()),
+        Some(filename) => Box::new(Compression::from_path(filename).compress(File::create(filename)?)?),
+    };
+    Ok(output)
+}
+
+/// A temporary file next to `path` that replaces it on `commit`, so
+/// readers of `path` never see a half written file. The temporary file
+/// is removed if it is dropped without being committed. A symlink is
+/// followed, its target is replaced, and the replacement keeps the
+/// permissions of the original.
+///
+/// # Usage:
+/// ```ignore
+/// let (in_place, file) = InPlace::create(Path::new("input/data.csv"))?;
+/// let mut writer = Writer::new(file);
+/// transform_stream(reader, &mut writer, &mut pipeline)?;
+/// drop(writer);
+/// in_place.commit(true)?; // keeps input/data.csv.bak
+/// ```
+pub struct InPlace {
+    path: PathBuf,
+    temp: Option<PathBuf>,
+}
+
+impl InPlace {
+    pub fn create(path: &Path) -> Result<(InPlace, File), Error> {
+        let path = &fs::canonicalize(path)?;
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => Err("in-place editing needs a file name")?
+        };
+        let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+        let in_place = InPlace { path: path.to_path_buf(), temp: Some(temp) };
+        Ok((in_place, file))
+    }
+
+    /// Atomically renames the temporary file over the original, first
+    /// copying the original to `<path>.bak` when `backup` is set.
+    pub fn commit(mut self, backup: bool) -> Result<(), Error> {
+        let temp = match self.temp.take() {
+            Some(temp) => temp,
+            None => return Ok(()),
+        };
+        let result = self.replace(&temp, backup);
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+
+    fn replace(&self, temp: &Path, backup: bool) -> Result<(), Error> {
+        File::open(temp)?.sync_all()?;
+        fs::set_permissions(temp, fs::metadata(&self.path)?.permissions())?;
+        if backup {
+            let mut name = self.path.clone().into_os_string();
+            name.push(".bak");
+            fs::copy(&self.path, name)?;
+        }
+        fs::rename(temp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for InPlace {
+    fn drop(&mut self) {
+        if let Some(temp) = self.temp.take() {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
 fn open(path: PathBuf) -> Result<File, Error> {
-   let file = File::open(path)?;
+   let file = File::open(&path).map_err(|e| {
+       let file = Some(path.to_string_lossy().into_owned());
+       Error::from(e).at(Position { file, ..Position::default() })
+   })?;
    Ok(file)
 }
-fn write(data: &str, filename: &str) -> Result<(), Error>  {
-   let mut buffer = File::create(filename)?;
-   buffer.write_all(data.as_bytes())?;
-   Ok(())
-}
 
 #[cfg(test)]
 mod test {
     use std::path::PathBuf;
-    use super::{load_csv, write_csv};
+    use super::{load_csv, write_csv, InPlace};
+    use std::{fs, path::Path};
     #[test]
     fn test_valid_load_csv(){
         let filename = PathBuf::from("./input/challenge.csv");
@@ -65,9 +229,60 @@ mod test {
 
     #[test]
     fn test_valid_write_csv(){
-        let filename = PathBuf::from("./input/challenge.csv");
         let modified_data = r"a,b,c,d,e\nf,g,h,i,j";
-        let output_file = write_csv(&modified_data, "output/test.csv");
+        let output_file = write_csv(modified_data, "output/test.csv");
         assert!(output_file.is_ok());
     }
+
+    #[test]
+    fn test_write_csv_as_is(){
+        // Written and read back as is, ragged rows and all.
+        let data = "a,b\n1\n2,3,4\n";
+        write_csv(data, "output/as_is.csv.gz").unwrap();
+        assert_eq!(fs::read("output/as_is.csv.gz").unwrap()[..2], [0x1f, 0x8b]);
+        assert_eq!(load_csv(PathBuf::from("output/as_is.csv.gz")).unwrap(), data);
+        fs::remove_file("output/as_is.csv.gz").unwrap();
+    }
+
+    #[test]
+    fn test_in_place(){
+        fs::create_dir_all("output/in_place").unwrap();
+        let path = Path::new("output/in_place/data.csv");
+        fs::write(path, "a,b\n1,2\n").unwrap();
+        let (in_place, _) = InPlace::create(path).unwrap();
+        let temp = in_place.temp.clone().unwrap();
+        assert!(temp.exists());
+        drop(in_place);
+        assert!(!temp.exists());
+
+        let (in_place, mut file) = InPlace::create(path).unwrap();
+        std::io::Write::write_all(&mut file, b"a,b\n3,4\n").unwrap();
+        drop(file);
+        in_place.commit(true).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "a,b\n3,4\n");
+        assert_eq!(fs::read_to_string("output/in_place/data.csv.bak").unwrap(), "a,b\n1,2\n");
+        fs::remove_dir_all("output/in_place").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_in_place_keeps_mode_and_link(){
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = Path::new("output/in_place_mode");
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join("data.csv");
+        let link = dir.join("link.csv");
+        fs::write(&path, "a,b\n1,2\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        symlink("data.csv", &link).unwrap();
+
+        let (in_place, mut file) = InPlace::create(&link).unwrap();
+        std::io::Write::write_all(&mut file, b"a,b\n3,4\n").unwrap();
+        drop(file);
+        in_place.commit(false).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a,b\n3,4\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,618 @@
+use super::Error;
+use super::transform::{field, position};
+use serde::de::{
+    self, value::BorrowedStrDeserializer, Deserialize, DeserializeSeed, Deserializer,
+    IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::ser::{self, Impossible, Serialize, Serializer};
+use serde::forward_to_deserialize_any;
+use std::str::FromStr;
+
+/// One record with its fields looked up by header.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::Record;
+/// use serde::Deserialize;
+/// #[derive(Deserialize)]
+/// struct Person<'a> { name: &'a str, age: Option<u32> }
+/// let headers = vec!["name".to_string(), "age".to_string()];
+/// let fields = vec!["John".to_string(), "32".to_string()];
+/// let record = Record::new(&headers, &fields);
+/// assert_eq!(record.get("name").unwrap(), "John");
+/// assert_eq!(record.parse::<u32>("age").unwrap(), 32);
+/// let person: Person = record.deserialize().unwrap();
+/// assert_eq!((person.name, person.age), ("John", Some(32)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Record<'a> {
+    headers: &'a [String],
+    fields: &'a [String],
+}
+
+impl<'a> Record<'a> {
+    pub fn new(headers: &'a [String], fields: &'a [String]) -> Self {
+        Record { headers, fields }
+    }
+
+    pub fn headers(&self) -> &'a [String] {
+        self.headers
+    }
+
+    pub fn fields(&self) -> &'a [String] {
+        self.fields
+    }
+
+    /// The field of `column`, given by name or as `#N` / `$N`.
+    pub fn get(&self, column: &str) -> Result<&'a str, Error> {
+        let index = position(self.headers, column)?;
+        Ok(field(self.fields, index)?)
+    }
+
+    /// Parses the field of `column`, ignoring surrounding whitespace.
+    pub fn parse<T: FromStr>(&self, column: &str) -> Result<T, Error> {
+        let value = self.get(column)?;
+        value.trim().parse().map_err(|_| {
+            Error::Data(format!("column `{}`: cannot parse `{}`", column, value))
+        })
+    }
+
+    /// Reads the record into `T`: structs and maps by header, tuples and
+    /// sequences by position. Empty fields read as `None`.
+    pub fn deserialize<T: Deserialize<'a>>(&self) -> Result<T, Error> {
+        T::deserialize(RecordDeserializer { record: *self })
+    }
+}
+
+/// Writes `row` as a record, returning its field names, empty for tuples
+/// and sequences, and its fields.
+pub(crate) fn to_record<T: Serialize + ?Sized>(row: &T) -> Result<(Vec<String>, Vec<String>), Error> {
+    let mut serializer = RecordSerializer { names: Vec::new(), fields: Vec::new() };
+    row.serialize(&mut serializer)?;
+    Ok((serializer.names, serializer.fields))
+}
+
+struct RecordDeserializer<'a> {
+    record: Record<'a>,
+}
+
+impl<'de> Deserializer<'de> for RecordDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(Fields { record: self.record, index: 0 })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Fields { record: self.record, index: 0 })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct enum identifier
+        ignored_any
+    }
+}
+
+/// Walks the fields of a record, as map entries keyed by header or as a
+/// sequence.
+struct Fields<'a> {
+    record: Record<'a>,
+    index: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn next_field<T, F>(&mut self, read: F) -> Result<T, Error>
+    where
+        F: FnOnce(Field<'a>) -> Result<T, Error>,
+    {
+        let index = self.index;
+        self.index += 1;
+        let value = field(self.record.fields, index)?;
+        read(Field(value)).map_err(|e| match (e, self.record.headers.get(index)) {
+            (Error::Data(message), Some(header)) => {
+                Error::Data(format!("column `{}`: {}", header, message))
+            }
+            (e, _) => e,
+        })
+    }
+}
+
+impl<'de> MapAccess<'de> for Fields<'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        match self.record.headers.get(self.index) {
+            Some(header) => seed.deserialize(BorrowedStrDeserializer::new(header)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        self.next_field(|value| seed.deserialize(value))
+    }
+}
+
+impl<'de> SeqAccess<'de> for Fields<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.index >= self.record.fields.len() {
+            return Ok(None);
+        }
+        self.next_field(|value| seed.deserialize(value)).map(Some)
+    }
+}
+
+/// A single field, parsed into whatever type is asked for.
+struct Field<'a>(&'a str);
+
+impl<'a> Field<'a> {
+    fn parse<T: FromStr>(&self, expected: &str) -> Result<T, Error> {
+        self.0.trim().parse().map_err(|_| {
+            Error::Data(format!("cannot parse `{}` as {}", self.0, expected))
+        })
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident, $expected:expr;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse($expected)?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Field<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool, "a boolean";
+        deserialize_i8 => visit_i8, "an integer";
+        deserialize_i16 => visit_i16, "an integer";
+        deserialize_i32 => visit_i32, "an integer";
+        deserialize_i64 => visit_i64, "an integer";
+        deserialize_i128 => visit_i128, "an integer";
+        deserialize_u8 => visit_u8, "an unsigned integer";
+        deserialize_u16 => visit_u16, "an unsigned integer";
+        deserialize_u32 => visit_u32, "an unsigned integer";
+        deserialize_u64 => visit_u64, "an unsigned integer";
+        deserialize_u128 => visit_u128, "an unsigned integer";
+        deserialize_f32 => visit_f32, "a number";
+        deserialize_f64 => visit_f64, "a number";
+        deserialize_char => visit_char, "a character";
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.trim().is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0.trim().is_empty() {
+            visitor.visit_unit()
+        } else {
+            Err(Error::Data(format!("expected an empty field, found `{}`", self.0)))
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.0.trim().into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+const NESTED: &str = "a CSV field can't hold a nested value";
+
+/// Collects the names and fields of a struct, map, tuple or sequence.
+struct RecordSerializer {
+    names: Vec<String>,
+    fields: Vec<String>,
+}
+
+impl RecordSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.fields.push(value.serialize(FieldSerializer)?);
+        Ok(())
+    }
+}
+
+fn not_a_record<T>() -> Result<T, Error> {
+    Err(Error::Program("a record must be a struct, map, tuple or sequence"))
+}
+
+impl Serializer for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_bool(self, _: bool) -> Result<(), Error> { not_a_record() }
+    fn serialize_i8(self, _: i8) -> Result<(), Error> { not_a_record() }
+    fn serialize_i16(self, _: i16) -> Result<(), Error> { not_a_record() }
+    fn serialize_i32(self, _: i32) -> Result<(), Error> { not_a_record() }
+    fn serialize_i64(self, _: i64) -> Result<(), Error> { not_a_record() }
+    fn serialize_u8(self, _: u8) -> Result<(), Error> { not_a_record() }
+    fn serialize_u16(self, _: u16) -> Result<(), Error> { not_a_record() }
+    fn serialize_u32(self, _: u32) -> Result<(), Error> { not_a_record() }
+    fn serialize_u64(self, _: u64) -> Result<(), Error> { not_a_record() }
+    fn serialize_f32(self, _: f32) -> Result<(), Error> { not_a_record() }
+    fn serialize_f64(self, _: f64) -> Result<(), Error> { not_a_record() }
+    fn serialize_char(self, _: char) -> Result<(), Error> { not_a_record() }
+    fn serialize_str(self, _: &str) -> Result<(), Error> { not_a_record() }
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), Error> { not_a_record() }
+    fn serialize_none(self) -> Result<(), Error> { not_a_record() }
+    fn serialize_unit(self) -> Result<(), Error> { not_a_record() }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> { not_a_record() }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Error> {
+        not_a_record()
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Error> {
+        not_a_record()
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        not_a_record()
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        not_a_record()
+    }
+}
+
+impl ser::SerializeSeq for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.names.push(key.serialize(FieldSerializer)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut RecordSerializer {
+    type Ok = ();
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.names.push(key.to_string());
+        self.push(value)
+    }
+    fn end(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Turns a single value into the text of a field, `None` being empty.
+struct FieldSerializer;
+
+macro_rules! serialize_display {
+    ($($method:ident: $ty:ty;)*) => {
+        $(
+            fn $method(self, value: $ty) -> Result<String, Error> {
+                Ok(value.to_string())
+            }
+        )*
+    };
+}
+
+impl Serializer for FieldSerializer {
+    type Ok = String;
+    type Error = Error;
+    type SerializeSeq = Impossible<String, Error>;
+    type SerializeTuple = Impossible<String, Error>;
+    type SerializeTupleStruct = Impossible<String, Error>;
+    type SerializeTupleVariant = Impossible<String, Error>;
+    type SerializeMap = Impossible<String, Error>;
+    type SerializeStruct = Impossible<String, Error>;
+    type SerializeStructVariant = Impossible<String, Error>;
+
+    serialize_display! {
+        serialize_bool: bool;
+        serialize_i8: i8;
+        serialize_i16: i16;
+        serialize_i32: i32;
+        serialize_i64: i64;
+        serialize_i128: i128;
+        serialize_u8: u8;
+        serialize_u16: u16;
+        serialize_u32: u32;
+        serialize_u64: u64;
+        serialize_u128: u128;
+        serialize_f32: f32;
+        serialize_f64: f64;
+        serialize_char: char;
+        serialize_str: &str;
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<String, Error> {
+        match std::str::from_utf8(value) {
+            Ok(value) => Ok(value.to_string()),
+            Err(_) => Err(Error::Program("a CSV field must be valid UTF-8")),
+        }
+    }
+
+    fn serialize_none(self) -> Result<String, Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String, Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<String, Error> {
+        Ok(String::new())
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<String, Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<String, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        Err(Error::Program(NESTED))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(Error::Program(NESTED))
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Error::Data(message.to_string())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Error::Data(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_record, Record};
+    use serde::{Deserialize, Serialize};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    enum Species {
+        Human,
+        Elf,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Person {
+        #[serde(rename = "Name")]
+        name: String,
+        #[serde(rename = "Age")]
+        age: u32,
+        #[serde(rename = "Species")]
+        species: Species,
+        #[serde(rename = "Note")]
+        note: Option<String>,
+    }
+
+    #[test]
+    fn test_deserialize_record() {
+        let headers = strings(&["Name", "Age", "Species", "Note"]);
+        let fields = strings(&["John", " 32 ", "Elf", ""]);
+        let record = Record::new(&headers, &fields);
+        let person: Person = record.deserialize().unwrap();
+        let expected = Person { name: "John".into(), age: 32, species: Species::Elf, note: None };
+        assert_eq!(person, expected);
+        let tuple: (String, u8) = record.deserialize().unwrap();
+        assert_eq!(tuple, ("John".to_string(), 32));
+
+        let fields = strings(&["John", "old", "Elf", ""]);
+        let e = Record::new(&headers, &fields).deserialize::<Person>().unwrap_err();
+        assert_eq!(e.to_string(), "column `Age`: cannot parse `old` as an unsigned integer");
+        assert!(Record::new(&headers, &fields).get("Town").is_err());
+    }
+
+    #[test]
+    fn test_serialize_record() {
+        let person = Person { name: "John".into(), age: 32, species: Species::Human, note: None };
+        let (names, fields) = to_record(&person).unwrap();
+        assert_eq!(names, strings(&["Name", "Age", "Species", "Note"]));
+        assert_eq!(fields, strings(&["John", "32", "Human", ""]));
+        assert_eq!(to_record(&("a", 1)).unwrap(), (Vec::new(), strings(&["a", "1"])));
+        assert!(to_record(&1).is_err());
+        assert!(to_record(&(vec![1],)).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use super::{Error, PathBuf};
+use super::read::open_csv;
+use super::transform::{field, position};
+use regex::Regex;
+use std::collections::{hash_map::Entry, HashMap};
+
+/// What `Set` writes into a cell.
+#[derive(Clone, Debug)]
+pub enum Replacement {
+    /// Overwrites the cell with a constant.
+    Value(String),
+    /// Replaces every match of the regex inside the cell, the replacement
+    /// may refer to capture groups as `$1` or `${name}`.
+    Regex(Regex, String),
+    /// Looks the cell up in a table, unknown values are left untouched.
+    Map(HashMap<String, String>),
+    /// Builds the cell from other columns of the same row.
+    Template(Template),
+}
+
+impl Replacement {
+    pub fn regex(pattern: &str, replacement: &str) -> Result<Replacement, Error> {
+        Ok(Replacement::Regex(Regex::new(pattern)?, replacement.to_string()))
+    }
+
+    /// Loads a mapping table from a CSV file with a header, the first
+    /// column holding old values and the second the new ones. Rows of
+    /// another width and old values listed twice are errors.
+    pub fn map_file(csv_file: PathBuf) -> Result<Replacement, Error> {
+        let mut reader = open_csv(csv_file)?;
+        if reader.read_record()?.is_none() {
+            Err("mapping file missing")?
+        }
+        let mut table = HashMap::new();
+        while let Some(record) = reader.read_record()? {
+            let mut record = record.into_iter();
+            let message = match (record.next(), record.next(), record.next()) {
+                (Some(old), Some(new), None) => match table.entry(old) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(new);
+                        continue;
+                    }
+                    Entry::Occupied(entry) => format!("duplicate key `{}` in mapping file", entry.key()),
+                },
+                _ => "mapping file needs two columns".to_string(),
+            };
+            return Err(Error::Data(message).at(reader.record_position()));
+        }
+        Ok(Replacement::Map(table))
+    }
+
+    pub fn template(template: &str) -> Result<Replacement, Error> {
+        Ok(Replacement::Template(Template::parse(template)?))
+    }
+
+    pub(crate) fn resolve(&mut self, headers: &[String]) -> Result<(), Error> {
+        if let Replacement::Template(template) = self {
+            template.resolve(headers)?;
+        }
+        Ok(())
+    }
+
+    /// Computes the new value of `record[index]`.
+    pub(crate) fn apply(&self, record: &[String], index: usize) -> Result<String, Error> {
+        let cell = field(record, index)?;
+        let value = match self {
+            Replacement::Value(value) => value.clone(),
+            Replacement::Regex(regex, replacement) => {
+                regex.replace_all(cell, replacement.as_str()).into_owned()
+            }
+            Replacement::Map(table) => table.get(cell).unwrap_or(cell).clone(),
+            Replacement::Template(template) => template.render(record)?,
+        };
+        Ok(value)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    Column(String, usize),
+}
+
+/// Text with `{Column}` placeholders, `{{` and `}}` stand for literal
+/// braces.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, Error> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut column = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => column.push(c),
+                            None => Err("unclosed `{` in template")?,
+                        }
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Column(column, 0));
+                }
+                '}' => Err("unmatched `}` in template")?,
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    pub fn resolve(&mut self, headers: &[String]) -> Result<(), Error> {
+        for part in &mut self.parts {
+            if let Part::Column(column, index) = part {
+                *index = position(headers, column)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, record: &[String]) -> Result<String, Error> {
+        let mut value = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => value.push_str(text),
+                Part::Column(_, index) => value.push_str(field(record, *index)?),
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Replacement, Template};
+    use std::path::PathBuf;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_regex_with_captures() {
+        let replacement = Replacement::regex(r"^(\w+) (\w+)$", "$2 $1").unwrap();
+        let record = strings(&["New Delhi"]);
+        assert_eq!(replacement.apply(&record, 0).unwrap(), "Delhi New");
+    }
+
+    #[test]
+    fn test_map_file() {
+        let replacement = Replacement::map_file(PathBuf::from("./input/city_codes.csv")).unwrap();
+        assert_eq!(replacement.apply(&strings(&["Tokyo"]), 0).unwrap(), "TYO");
+        assert_eq!(replacement.apply(&strings(&["Atlantis"]), 0).unwrap(), "Atlantis");
+
+        let path = std::env::temp_dir().join("csv_challenge_bad_map.csv");
+        for (data, message) in &[
+            ("old,new\nTokyo,TYO\nOsaka,OSA\nTokyo,HND\n", ":4: duplicate key `Tokyo` in mapping file"),
+            ("old,new\nTokyo,TYO,JP\n", ":2: mapping file needs two columns"),
+        ] {
+            std::fs::write(&path, data).unwrap();
+            let error = Replacement::map_file(path.clone()).unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_template() {
+        let headers = strings(&["City", "Zip", "Place"]);
+        let mut replacement = Replacement::template("{City}-{Zip} {{x}}").unwrap();
+        replacement.resolve(&headers).unwrap();
+        let record = strings(&["Tokyo", "100", ""]);
+        assert_eq!(replacement.apply(&record, 2).unwrap(), "Tokyo-100 {x}");
+        assert!(Template::parse("{City").is_err());
+        assert!(Template::parse("{Town}").unwrap().resolve(&headers).is_err());
+    }
+}
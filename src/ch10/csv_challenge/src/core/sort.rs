@@ -0,0 +1,494 @@
+use super::{Error, File, PathBuf};
+use super::format::Output;
+use super::parse::{Reader, Source, Writer};
+use super::transform::positions;
+use std::{
+    cmp::Ordering,
+    env, fs,
+    io::{self, BufReader},
+    path::Path,
+    process,
+};
+
+/// Memory used for buffered records before they are spilled to disk.
+pub const DEFAULT_MEMORY_LIMIT: usize = 100 * 1024 * 1024;
+
+/// Runs merged at once, more are merged in several passes.
+const MERGE_WIDTH: usize = 64;
+
+/// A column to sort by.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortKey {
+    column: String,
+    numeric: bool,
+    descending: bool,
+    index: usize,
+}
+
+impl SortKey {
+    pub fn new(column: &str, numeric: bool, descending: bool) -> Self {
+        SortKey { column: column.to_string(), numeric, descending, index: 0 }
+    }
+
+    /// Parses `COLUMN[:num|:lex][:asc|:desc]`, sorting lexically and
+    /// ascending by default.
+    pub fn parse(spec: &str) -> Result<SortKey, Error> {
+        let mut column = spec;
+        let (mut numeric, mut descending) = (false, false);
+        while let Some(i) = column.rfind(':') {
+            match &column[i + 1..] {
+                "num" | "numeric" | "n" => numeric = true,
+                "lex" | "lexical" => numeric = false,
+                "asc" => descending = false,
+                "desc" => descending = true,
+                _ => break,
+            }
+            column = &column[..i];
+        }
+        if column.is_empty() {
+            Err("sort key needs a column, e.g. Age:num:desc")?
+        }
+        Ok(SortKey::new(column, numeric, descending))
+    }
+
+    fn compare(&self, a: &[String], b: &[String]) -> Ordering {
+        let (a, b) = (&a[self.index], &b[self.index]);
+        let ordering = if self.numeric {
+            // Numbers come before anything that isn't one, `NaN` and
+            // infinities included, which keeps the order total.
+            let number = |s: &str| s.trim().parse::<f64>().ok().filter(|n| n.is_finite());
+            match (number(a), number(b)) {
+                (Some(x), Some(y)) => x.total_cmp(&y),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => a.cmp(b),
+            }
+        } else {
+            a.cmp(b)
+        };
+        if self.descending { ordering.reverse() } else { ordering }
+    }
+}
+
+/// Sorts records by one or more keys, stably. Input larger than the
+/// memory limit is sorted in runs spilled to a temporary directory and
+/// merged.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Sort, SortKey, Writer};
+/// let data = "Name,Age\nJohn,32\nFlip,12\nBrad,32\n";
+/// let mut writer = Writer::new(Vec::new());
+/// Sort::new(vec![SortKey::parse("Age:num:desc").unwrap()])
+///     .sort(parse_records(data), &mut writer)
+///     .unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(output, "Name,Age\nJohn,32\nBrad,32\nFlip,12\n");
+/// ```
+pub struct Sort {
+    keys: Vec<SortKey>,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+}
+
+impl Sort {
+    pub fn new(keys: Vec<SortKey>) -> Self {
+        Sort { keys, memory_limit: DEFAULT_MEMORY_LIMIT, temp_dir: env::temp_dir() }
+    }
+
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Where spill files go, the system temporary directory by default.
+    pub fn temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    /// Writes the records of `reader` to `output` in order, returning how
+    /// many were written.
+    pub fn sort<S: Source, O: Output + ?Sized>(&mut self, mut reader: S, output: &mut O) -> Result<u64, Error> {
+        let headers = reader.headers()?;
+        let indices = positions(&headers, &self.keys.iter().map(|k| k.column.clone()).collect::<Vec<_>>())?;
+        for (key, index) in self.keys.iter_mut().zip(indices) {
+            key.index = index;
+        }
+        output.write_headers(&headers, reader.synthetic_headers())?;
+        let keys = &self.keys;
+        let compare = |a: &[String], b: &[String]| {
+            keys.iter().map(|key| key.compare(a, b)).find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+        };
+        let mut sorter = ExternalSorter::new(compare, self.memory_limit, &self.temp_dir)?;
+        let mut seq = 0;
+        while let Some(record) = reader.read_record()? {
+            sorter.push(seq, record)?;
+            seq += 1;
+        }
+        for entry in sorter.finish()? {
+            output.write_record(&entry?.1)?;
+        }
+        output.finish()?;
+        Ok(seq)
+    }
+}
+
+/// Keeps the first record of every key, in input order. Like `Sort`, it
+/// spills to disk when the input doesn't fit the memory limit.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Dedup, Writer};
+/// let data = "Name,City\nJohn,Tokyo\nFlip,Paris\nJohn,Cracow\n";
+/// let mut writer = Writer::new(Vec::new());
+/// let removed = Dedup::new(&["Name"]).dedup(parse_records(data), &mut writer).unwrap();
+/// let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!((removed, output.as_str()), (1, "Name,City\nJohn,Tokyo\nFlip,Paris\n"));
+/// ```
+pub struct Dedup {
+    columns: Vec<String>,
+    memory_limit: usize,
+    temp_dir: PathBuf,
+}
+
+impl Dedup {
+    /// Records are duplicates when they agree on `columns`, or on every
+    /// column if `columns` is empty.
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        Dedup {
+            columns: columns.iter().map(|c| c.as_ref().to_string()).collect(),
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            temp_dir: env::temp_dir(),
+        }
+    }
+
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    pub fn temp_dir(mut self, dir: PathBuf) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+
+    /// Writes the unique records of `reader` to `output`, returning how
+    /// many duplicates were left out.
+    pub fn dedup<S: Source, O: Output + ?Sized>(&self, mut reader: S, output: &mut O) -> Result<u64, Error> {
+        let headers = reader.headers()?;
+        let indices = if self.columns.is_empty() {
+            (0..headers.len()).collect()
+        } else {
+            positions(&headers, &self.columns)?
+        };
+        output.write_headers(&headers, reader.synthetic_headers())?;
+        let by_key = |a: &[String], b: &[String]| {
+            indices.iter().map(|&i| a[i].cmp(&b[i])).find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+        };
+        // Sort by key to find the first record of every key, then back
+        // into input order.
+        let mut keyed = ExternalSorter::new(by_key, self.memory_limit, &self.temp_dir)?;
+        let mut seq = 0;
+        while let Some(record) = reader.read_record()? {
+            keyed.push(seq, record)?;
+            seq += 1;
+        }
+        let in_order = |_: &[String], _: &[String]| Ordering::Equal;
+        let mut unique = ExternalSorter::new(in_order, self.memory_limit, &self.temp_dir)?;
+        let mut last: Option<Vec<String>> = None;
+        let mut removed = 0;
+        for entry in keyed.finish()? {
+            let (seq, record) = entry?;
+            if last.as_ref().is_some_and(|last| by_key(last, &record).is_eq()) {
+                removed += 1;
+                continue;
+            }
+            unique.push(seq, record.clone())?;
+            last = Some(record);
+        }
+        for entry in unique.finish()? {
+            output.write_record(&entry?.1)?;
+        }
+        output.finish()?;
+        Ok(removed)
+    }
+}
+
+/// A record with its position in the input, which breaks ties so the
+/// sort is stable.
+pub(crate) type Entry = (u64, Vec<String>);
+
+/// Roughly the memory `record` takes.
+pub(crate) fn record_size(record: &[String]) -> usize {
+    record.iter().map(|f| f.len() + 24).sum::<usize>() + 48
+}
+
+/// A directory of spill files, removed with its content when dropped.
+struct SpillDir {
+    path: PathBuf,
+    files: usize,
+}
+
+impl SpillDir {
+    fn create(parent: &Path) -> Result<SpillDir, Error> {
+        for n in 0.. {
+            let path = parent.join(format!("csv_challenge.{}.{}", process::id(), n));
+            match fs::create_dir(&path) {
+                Ok(()) => return Ok(SpillDir { path, files: 0 }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    fn next_file(&mut self) -> PathBuf {
+        self.files += 1;
+        self.path.join(format!("run{}.csv", self.files))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Sorts entries in memory up to a limit, then in sorted runs on disk.
+pub(crate) struct ExternalSorter<F> {
+    compare: F,
+    memory_limit: usize,
+    parent: PathBuf,
+    dir: Option<SpillDir>,
+    buffer: Vec<Entry>,
+    bytes: usize,
+    runs: Vec<PathBuf>,
+}
+
+impl<F: Fn(&[String], &[String]) -> Ordering> ExternalSorter<F> {
+    pub(crate) fn new(compare: F, memory_limit: usize, parent: &Path) -> Result<Self, Error> {
+        Ok(ExternalSorter {
+            compare,
+            memory_limit,
+            parent: parent.to_path_buf(),
+            dir: None,
+            buffer: Vec::new(),
+            bytes: 0,
+            runs: Vec::new(),
+        })
+    }
+
+    pub(crate) fn push(&mut self, seq: u64, record: Vec<String>) -> Result<(), Error> {
+        self.bytes += record_size(&record);
+        self.buffer.push((seq, record));
+        if self.bytes >= self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort_buffer(&mut self) {
+        let compare = &self.compare;
+        self.buffer.sort_by(|a, b| compare(&a.1, &b.1).then(a.0.cmp(&b.0)));
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.sort_buffer();
+        if self.dir.is_none() {
+            self.dir = Some(SpillDir::create(&self.parent)?);
+        }
+        let path = self.dir.as_mut().map(SpillDir::next_file).unwrap_or_default();
+        let mut writer = Writer::new(File::create(&path)?);
+        for entry in self.buffer.drain(..) {
+            write_entry(&mut writer, entry)?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// The entries in order, merged from disk if any run was spilled.
+    pub(crate) fn finish(mut self) -> Result<Merge<F>, Error> {
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            let buffer = std::mem::take(&mut self.buffer);
+            return Ok(Merge { compare: self.compare, heads: Vec::new(), runs: Vec::new(), buffer: buffer.into_iter(), _dir: None });
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut dir = self.dir.take();
+        let mut runs = std::mem::take(&mut self.runs);
+        while runs.len() > MERGE_WIDTH {
+            let path = dir.as_mut().map(SpillDir::next_file).unwrap_or_default();
+            let group: Vec<PathBuf> = runs.drain(..MERGE_WIDTH).collect();
+            let mut writer = Writer::new(File::create(&path)?);
+            for entry in Merge::open(&self.compare, &group)? {
+                write_entry(&mut writer, entry?)?;
+            }
+            writer.flush()?;
+            for run in group {
+                fs::remove_file(run)?;
+            }
+            runs.push(path);
+        }
+        let mut merge = Merge::open(self.compare, &runs)?;
+        merge._dir = dir;
+        Ok(merge)
+    }
+}
+
+fn write_entry<W: io::Write>(writer: &mut Writer<W>, (seq, mut record): Entry) -> Result<(), Error> {
+    // The sequence number also keeps an empty single field record from
+    // being written as a blank line.
+    record.insert(0, seq.to_string());
+    writer.write_record(&record)
+}
+
+type Run = Reader<BufReader<File>>;
+
+fn read_entry(run: &mut Run) -> Result<Option<Entry>, Error> {
+    match run.read_record()? {
+        Some(mut record) => {
+            let seq = record.remove(0).parse().map_err(|_| "corrupt spill file")?;
+            Ok(Some((seq, record)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Merges sorted runs, or hands out a buffer sorted in memory.
+pub(crate) struct Merge<F> {
+    compare: F,
+    heads: Vec<Option<Entry>>,
+    runs: Vec<Run>,
+    buffer: std::vec::IntoIter<Entry>,
+    _dir: Option<SpillDir>,
+}
+
+impl<F: Fn(&[String], &[String]) -> Ordering> Merge<F> {
+    fn open(compare: F, paths: &[PathBuf]) -> Result<Self, Error> {
+        let mut runs = Vec::with_capacity(paths.len());
+        let mut heads = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut run = Reader::new(BufReader::new(File::open(path)?));
+            heads.push(read_entry(&mut run)?);
+            runs.push(run);
+        }
+        Ok(Merge { compare, heads, runs, buffer: Vec::new().into_iter(), _dir: None })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>, Error> {
+        if self.runs.is_empty() {
+            return Ok(self.buffer.next());
+        }
+        let mut min: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let head = match head {
+                Some(head) => head,
+                None => continue,
+            };
+            let smaller = match min.and_then(|m| self.heads[m].as_ref()) {
+                Some(best) => (self.compare)(&head.1, &best.1).then(head.0.cmp(&best.0)).is_lt(),
+                None => true,
+            };
+            if smaller {
+                min = Some(i);
+            }
+        }
+        match min {
+            Some(i) => {
+                let next = read_entry(&mut self.runs[i])?;
+                Ok(std::mem::replace(&mut self.heads[i], next))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<F: Fn(&[String], &[String]) -> Ordering> Iterator for Merge<F> {
+    type Item = Result<Entry, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dedup, Sort, SortKey};
+    use crate::core::parse::{parse_records, Writer};
+    use std::{fs, path::PathBuf};
+
+    fn sort(data: &str, keys: &[&str], memory_limit: usize) -> String {
+        let keys = keys.iter().map(|k| SortKey::parse(k).unwrap()).collect();
+        let mut writer = Writer::new(Vec::new());
+        Sort::new(keys)
+            .memory_limit(memory_limit)
+            .temp_dir(PathBuf::from("output/sort"))
+            .sort(parse_records(data), &mut writer)
+            .unwrap();
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+
+    fn spill_dirs(dir: &str) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("csv_challenge."))
+            .count()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(SortKey::parse("Age:num:desc").unwrap(), SortKey::new("Age", true, true));
+        assert_eq!(SortKey::parse("a:b").unwrap(), SortKey::new("a:b", false, false));
+        assert!(SortKey::parse(":desc").is_err());
+    }
+
+    #[test]
+    fn test_external_sort_is_stable() {
+        fs::create_dir_all("output/sort").unwrap();
+        let mut data = String::from("n,k\n");
+        for i in 0..500 {
+            data.push_str(&format!("{},{}\n", i, (i * 7) % 10));
+        }
+        let in_memory = sort(&data, &["k:num", "n:num:desc"], usize::MAX);
+        // A tiny limit spills nearly every record, more runs than one
+        // merge pass takes.
+        assert_eq!(sort(&data, &["k:num", "n:num:desc"], 1), in_memory);
+        assert!(in_memory.starts_with("n,k\n490,0\n480,0\n"));
+        let stable = sort(&data, &["k"], 200);
+        assert!(stable.starts_with("n,k\n0,0\n10,0\n20,0\n"));
+        assert_eq!(spill_dirs("output/sort"), 0);
+    }
+
+    #[test]
+    fn test_non_finite_numbers() {
+        let mut data = String::from("x\n");
+        for i in 0..40 {
+            data.push_str(if i % 3 == 0 { "NaN\n" } else if i % 7 == 0 { "inf\n" } else { "1\n" });
+            data.push_str(&format!("{}\n", 40 - i));
+        }
+        let sorted = sort(&data, &["x:num"], usize::MAX);
+        assert!(sorted.starts_with("x\n1\n1\n"));
+        assert!(sorted.ends_with("NaN\nNaN\ninf\ninf\ninf\ninf\n"));
+    }
+
+    #[test]
+    fn test_dedup() {
+        fs::create_dir_all("output/dedup").unwrap();
+        let data = "Name,City\nJohn,Tokyo\nFlip,Paris\nJohn,Cracow\nFlip,Paris\nAmy,Dublin\n";
+        for limit in &[1, usize::MAX] {
+            let mut writer = Writer::new(Vec::new());
+            let dedup = Dedup::new(&["Name"]).memory_limit(*limit).temp_dir(PathBuf::from("output/dedup"));
+            assert_eq!(dedup.dedup(parse_records(data), &mut writer).unwrap(), 2);
+            let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+            assert_eq!(output, "Name,City\nJohn,Tokyo\nFlip,Paris\nAmy,Dublin\n");
+        }
+        let mut writer = Writer::new(Vec::new());
+        let removed = Dedup::new::<&str>(&[]).dedup(parse_records(data), &mut writer).unwrap();
+        assert_eq!(removed, 1);
+        assert_eq!(spill_dirs("output/dedup"), 0);
+    }
+}
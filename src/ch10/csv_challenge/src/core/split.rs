@@ -0,0 +1,282 @@
+use super::{Error, File, PathBuf};
+use super::compress::{CompressWriter, Compression};
+use super::dialect::Dialect;
+use super::format::Output;
+use super::parse::{Source, Writer};
+use super::transform::{field, position};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::OpenOptions,
+};
+
+/// Where `Split` starts a new file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SplitBy {
+    /// After this many records.
+    Rows(u64),
+    /// Before a file would grow past this many bytes, header included.
+    /// A record larger than that gets a file of its own. Compressed files
+    /// are measured before compression.
+    Bytes(u64),
+    /// One file per distinct value of the column.
+    Column(String),
+}
+
+/// Writes the records of one input to several files, each with the
+/// header.
+///
+/// Files are named after a prefix, numbered as `<prefix>_0001.csv` or,
+/// split by a column, named by its value as `<prefix>_Tokyo.csv`, with
+/// characters unsafe in file names replaced by `_`. An extension ending
+/// in `.gz` or `.zst` compresses them.
+///
+/// Split by a column, at most `max_open` files are kept open at once, the
+/// oldest being closed and later appended to when needed.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Split, SplitBy};
+/// let prefix = std::env::temp_dir().join("cities");
+/// let data = "Name,City\nJohn,Tokyo\nFlip,Paris\nBrad,Tokyo\n";
+/// let files = Split::new(SplitBy::Column("City".to_string()), &prefix.to_string_lossy())
+///     .split(parse_records(data))
+///     .unwrap();
+/// assert_eq!(std::fs::read_to_string(&files[0]).unwrap(), "Name,City\nJohn,Tokyo\nBrad,Tokyo\n");
+/// assert!(files[1].ends_with("cities_Paris.csv"));
+/// # for file in files { std::fs::remove_file(file).unwrap(); }
+/// ```
+pub struct Split {
+    by: SplitBy,
+    prefix: String,
+    extension: String,
+    dialect: Dialect,
+    max_open: usize,
+}
+
+/// A numbered file being written, and how much it holds.
+struct Part {
+    writer: Writer<CompressWriter<File>>,
+    bytes: u64,
+    records: u64,
+}
+
+impl Split {
+    pub fn new(by: SplitBy, prefix: &str) -> Self {
+        Split { by, prefix: prefix.to_string(), extension: "csv".to_string(), dialect: Dialect::default(), max_open: 64 }
+    }
+
+    /// The extension of the files, `csv` by default.
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.extension = extension.trim_start_matches('.').to_string();
+        self
+    }
+
+    /// The dialect the files are written in.
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn max_open(mut self, files: usize) -> Self {
+        self.max_open = files.max(1);
+        self
+    }
+
+    /// Splits `reader`, returning the files written in the order they
+    /// were started.
+    pub fn split<S: Source>(&self, mut reader: S) -> Result<Vec<PathBuf>, Error> {
+        let headers = reader.headers()?;
+        let synthetic = reader.synthetic_headers();
+        let mut files = Vec::new();
+        match &self.by {
+            SplitBy::Rows(0) | SplitBy::Bytes(0) => Err("split size must be above 0")?,
+            SplitBy::Rows(_) | SplitBy::Bytes(_) => {
+                let header = self.header_size(&headers, synthetic);
+                let mut part: Option<Part> = None;
+                let mut line = String::new();
+                while let Some(record) = reader.read_record()? {
+                    line.clear();
+                    self.dialect.format_record(&mut line, &record);
+                    let size = line.len() as u64;
+                    let full = match (&self.by, &part) {
+                        (_, None) => true,
+                        (SplitBy::Rows(rows), Some(part)) => part.records == *rows,
+                        // A file gets at least one record.
+                        (SplitBy::Bytes(limit), Some(part)) => part.records > 0 && part.bytes + size > *limit,
+                        (SplitBy::Column(_), Some(_)) => false,
+                    };
+                    if full {
+                        if let Some(mut done) = part.take() {
+                            done.writer.finish()?;
+                        }
+                        let path = PathBuf::from(format!("{}_{:04}.{}", self.prefix, files.len() + 1, self.extension));
+                        let mut writer = self.create(&path, false)?;
+                        writer.write_headers(&headers, synthetic)?;
+                        files.push(path);
+                        part = Some(Part { writer, bytes: header, records: 0 });
+                    }
+                    if let Some(part) = part.as_mut() {
+                        part.writer.write_record(&record)?;
+                        part.bytes += size;
+                        part.records += 1;
+                    }
+                }
+                if let Some(mut done) = part {
+                    done.writer.finish()?;
+                }
+            }
+            SplitBy::Column(column) => {
+                let index = position(&headers, column)?;
+                // Paths by value, and the open files oldest first.
+                let mut paths: HashMap<String, PathBuf> = HashMap::new();
+                let mut taken = HashSet::new();
+                let mut open: HashMap<String, Writer<CompressWriter<File>>> = HashMap::new();
+                let mut opened = VecDeque::new();
+                while let Some(record) = reader.read_record()? {
+                    let value = field(&record, index).map_err(|e| e.at(reader.record_position()))?;
+                    if !open.contains_key(value) {
+                        if open.len() >= self.max_open {
+                            if let Some(oldest) = opened.pop_front() {
+                                if let Some(mut writer) = open.remove(&oldest) {
+                                    writer.finish()?;
+                                }
+                            }
+                        }
+                        let writer = match paths.get(value) {
+                            Some(path) => self.create(path, true)?,
+                            None => {
+                                let path = self.unique_path(value, &mut taken);
+                                paths.insert(value.clone(), path.clone());
+                                files.push(path.clone());
+                                let mut writer = self.create(&path, false)?;
+                                writer.write_headers(&headers, synthetic)?;
+                                writer
+                            }
+                        };
+                        open.insert(value.clone(), writer);
+                        opened.push_back(value.clone());
+                    }
+                    if let Some(writer) = open.get_mut(value) {
+                        writer.write_record(&record)?;
+                    }
+                }
+                for writer in open.values_mut() {
+                    writer.finish()?;
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn header_size(&self, headers: &[String], synthetic: bool) -> u64 {
+        if synthetic {
+            return 0;
+        }
+        let mut line = String::new();
+        self.dialect.format_record(&mut line, headers);
+        line.len() as u64
+    }
+
+    /// The path for a column value, made safe as a file name and told
+    /// apart from the paths of other values that end up the same.
+    fn unique_path(&self, value: &str, taken: &mut HashSet<PathBuf>) -> PathBuf {
+        let mut name: String =
+            value.chars().map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' }).collect();
+        if name.is_empty() {
+            name = "empty".to_string();
+        } else if name.starts_with('.') {
+            name.insert(0, '_');
+        }
+        let mut path = PathBuf::from(format!("{}_{}.{}", self.prefix, name, self.extension));
+        let mut n = 1;
+        while !taken.insert(path.clone()) {
+            n += 1;
+            path = PathBuf::from(format!("{}_{}_{}.{}", self.prefix, name, n, self.extension));
+        }
+        path
+    }
+
+    fn create(&self, path: &PathBuf, append: bool) -> Result<Writer<CompressWriter<File>>, Error> {
+        let file: File = OpenOptions::new().write(true).create(true).append(append).truncate(!append).open(path)?;
+        let compression = Compression::from_path(&path.to_string_lossy());
+        Ok(Writer::new(compression.compress(file)?).with_dialect(self.dialect.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Split, SplitBy};
+    use crate::core::compress::Compression;
+    use crate::core::parse::parse_records;
+    use std::{fs, io::Read, path::PathBuf};
+
+    fn split(by: SplitBy, name: &str, data: &str, max_open: usize) -> Vec<(String, String)> {
+        let prefix = std::env::temp_dir().join(format!("csv_challenge_split_{}", name));
+        let files = Split::new(by, &prefix.to_string_lossy()).max_open(max_open).split(parse_records(data)).unwrap();
+        files
+            .iter()
+            .map(|file: &PathBuf| {
+                let content = fs::read_to_string(file).unwrap();
+                fs::remove_file(file).unwrap();
+                let name = file.file_name().unwrap().to_string_lossy().replace(&format!("csv_challenge_split_{}_", name), "");
+                (name, content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_by_size() {
+        let data = "Id,Note\n1,a\n2,bb\n3,\"c\nc\"\n4,d\n5,e\n";
+        let files = split(SplitBy::Rows(2), "rows", data, 64);
+        assert_eq!(
+            files,
+            [
+                ("0001.csv".to_string(), "Id,Note\n1,a\n2,bb\n".to_string()),
+                ("0002.csv".to_string(), "Id,Note\n3,\"c\nc\"\n4,d\n".to_string()),
+                ("0003.csv".to_string(), "Id,Note\n5,e\n".to_string()),
+            ]
+        );
+        // 8 bytes of header, then records of 4, 5, 8, 4 and 4 bytes.
+        let files = split(SplitBy::Bytes(17), "bytes", data, 64);
+        let sizes: Vec<usize> = files.iter().map(|(_, content)| content.len()).collect();
+        assert_eq!(sizes, [17, 16, 16]);
+        let files = split(SplitBy::Bytes(1), "tiny", "Id\n1\n2\n", 64);
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn test_split_by_column() {
+        let data = "City,N\nTokyo,1\nNew York,2\nTokyo,3\n,4\nNew/York,5\nParis,6\nNew York,7\n";
+        let files = split(SplitBy::Column("City".to_string()), "city", data, 2);
+        assert_eq!(
+            files,
+            [
+                ("Tokyo.csv".to_string(), "City,N\nTokyo,1\nTokyo,3\n".to_string()),
+                ("New_York.csv".to_string(), "City,N\nNew York,2\nNew York,7\n".to_string()),
+                ("empty.csv".to_string(), "City,N\n,4\n".to_string()),
+                ("New_York_2.csv".to_string(), "City,N\nNew/York,5\n".to_string()),
+                ("Paris.csv".to_string(), "City,N\nParis,6\n".to_string()),
+            ]
+        );
+    }
+    #[test]
+    fn test_compressed_parts() {
+        let prefix = std::env::temp_dir().join("csv_challenge_split_zst");
+        let data = "City,N\nTokyo,1\nParis,2\nTokyo,3\n";
+        let files = Split::new(SplitBy::Column("City".to_string()), &prefix.to_string_lossy())
+            .extension("csv.zst")
+            .max_open(1)
+            .split(parse_records(data))
+            .unwrap();
+        let mut contents = Vec::new();
+        for file in files {
+            let mut content = String::new();
+            let bytes = fs::read(&file).unwrap();
+            Compression::decompress(&bytes[..]).unwrap().read_to_string(&mut content).unwrap();
+            fs::remove_file(file).unwrap();
+            contents.push(content);
+        }
+        // Tokyo was closed for Paris, then appended to as a second frame.
+        assert_eq!(contents, ["City,N\nTokyo,1\nTokyo,3\n", "City,N\nParis,2\n"]);
+    }
+}
@@ -0,0 +1,339 @@
+use super::Error;
+use super::parse::Source;
+use std::{collections::HashMap, fmt, str::FromStr};
+
+/// Distinct values tracked per column, beyond this new values are no
+/// longer counted so memory use stays bounded.
+pub const DISTINCT_LIMIT: usize = 100_000;
+
+/// The type inferred for a column from its non-empty values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Every value is empty.
+    Empty,
+    Bool,
+    Int,
+    /// Numbers, some of them with a fraction or exponent.
+    Float,
+    /// ISO 8601 dates, `YYYY-MM-DD`.
+    Date,
+    Text,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Empty => "empty",
+            Kind::Bool => "bool",
+            Kind::Int => "int",
+            Kind::Float => "float",
+            Kind::Date => "date",
+            Kind::Text => "text",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Kind {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bool" | "boolean" => Ok(Kind::Bool),
+            "int" | "integer" => Ok(Kind::Int),
+            "float" | "number" => Ok(Kind::Float),
+            "date" => Ok(Kind::Date),
+            "text" | "string" => Ok(Kind::Text),
+            _ => Err("type must be one of bool, int, float, date, text"),
+        }
+    }
+}
+
+impl Kind {
+    /// Whether a non-empty `value` is of this type, integers being floats
+    /// too and anything being text.
+    pub fn admits(self, value: &str) -> bool {
+        match self {
+            Kind::Empty => false,
+            Kind::Bool => classify(value) == BOOL,
+            Kind::Int => value.parse::<i64>().is_ok(),
+            Kind::Float => is_number(value),
+            Kind::Date => is_date(value),
+            Kind::Text => true,
+        }
+    }
+}
+
+const BOOL: u8 = 1;
+const INT: u8 = 2;
+const FLOAT: u8 = 4;
+const DATE: u8 = 8;
+const TEXT: u8 = 16;
+
+fn classify(value: &str) -> u8 {
+    if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+        BOOL
+    } else if value.parse::<i64>().is_ok() {
+        INT
+    } else if is_number(value) {
+        FLOAT
+    } else if is_date(value) {
+        DATE
+    } else {
+        TEXT
+    }
+}
+
+/// A finite number, `inf` and `NaN` are text.
+fn is_number(value: &str) -> bool {
+    value.parse::<f64>().is_ok_and(f64::is_finite) && value.bytes().any(|b| b.is_ascii_digit())
+}
+
+fn is_date(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return false;
+    }
+    let number = |range: std::ops::Range<usize>| -> Option<u32> {
+        let digits = &value[range];
+        if digits.bytes().all(|b| b.is_ascii_digit()) { digits.parse().ok() } else { None }
+    };
+    let (year, month, day) = match (number(0..4), number(5..7), number(8..10)) {
+        (Some(year), Some(month), Some(day)) => (year, month, day),
+        _ => return false,
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    day >= 1 && day <= days
+}
+
+/// A computed number with at most four decimals, without trailing zeros.
+pub(crate) fn format_number(number: f64) -> String {
+    let number = format!("{:.4}", number);
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    if number == "-0" { "0".to_string() } else { number.to_string() }
+}
+
+/// The summary of one column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnStats {
+    pub name: String,
+    pub kind: Kind,
+    /// Records seen, empty values included.
+    pub count: u64,
+    /// Values that are empty or whitespace only.
+    pub empty: u64,
+    /// Distinct non-empty values, a lower bound unless `exact`.
+    pub distinct: usize,
+    pub exact: bool,
+    /// Smallest and largest values, compared as numbers for numeric
+    /// columns and as text otherwise.
+    pub min: Option<String>,
+    pub max: Option<String>,
+    /// Mean of numeric columns.
+    pub mean: Option<f64>,
+    /// The most frequent non-empty values with their counts.
+    pub top: Vec<(String, u64)>,
+}
+
+impl ColumnStats {
+    pub const HEADERS: [&'static str; 9] =
+        ["column", "type", "count", "empty", "distinct", "min", "max", "mean", "top"];
+
+    /// The summary as a record under `HEADERS`.
+    pub fn to_record(&self) -> Vec<String> {
+        let distinct = if self.exact { self.distinct.to_string() } else { format!(">={}", self.distinct) };
+        let mean = self.mean.map(format_number).unwrap_or_default();
+        let top: Vec<String> = self.top.iter().map(|(value, n)| format!("{} ({})", value, n)).collect();
+        vec![
+            self.name.clone(),
+            self.kind.to_string(),
+            self.count.to_string(),
+            self.empty.to_string(),
+            distinct,
+            self.min.clone().unwrap_or_default(),
+            self.max.clone().unwrap_or_default(),
+            mean,
+            top.join(", "),
+        ]
+    }
+}
+
+#[derive(Default)]
+struct Column {
+    kinds: u8,
+    count: u64,
+    empty: u64,
+    values: HashMap<String, u64>,
+    exact: bool,
+    sum: f64,
+    min_number: Option<(f64, String)>,
+    max_number: Option<(f64, String)>,
+    min_text: Option<String>,
+    max_text: Option<String>,
+}
+
+impl Column {
+    fn add(&mut self, value: &str) {
+        self.count += 1;
+        let value = value.trim();
+        if value.is_empty() {
+            self.empty += 1;
+            return;
+        }
+        let kind = classify(value);
+        self.kinds |= kind;
+        if let Some(n) = self.values.get_mut(value) {
+            *n += 1;
+        } else if self.values.len() < DISTINCT_LIMIT {
+            self.values.insert(value.to_string(), 1);
+        } else {
+            self.exact = false;
+        }
+        if kind & (INT | FLOAT) != 0 {
+            let number: f64 = value.parse().unwrap_or_default();
+            self.sum += number;
+            if self.min_number.as_ref().is_none_or(|(min, _)| number < *min) {
+                self.min_number = Some((number, value.to_string()));
+            }
+            if self.max_number.as_ref().is_none_or(|(max, _)| number > *max) {
+                self.max_number = Some((number, value.to_string()));
+            }
+        }
+        if self.min_text.as_deref().is_none_or(|min| value < min) {
+            self.min_text = Some(value.to_string());
+        }
+        if self.max_text.as_deref().is_none_or(|max| value > max) {
+            self.max_text = Some(value.to_string());
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match self.kinds {
+            0 => Kind::Empty,
+            BOOL => Kind::Bool,
+            INT => Kind::Int,
+            k if k & !(INT | FLOAT) == 0 => Kind::Float,
+            DATE => Kind::Date,
+            _ => Kind::Text,
+        }
+    }
+
+    fn summary(&self, name: &str, top: usize) -> ColumnStats {
+        let kind = self.kind();
+        let numeric = kind == Kind::Int || kind == Kind::Float;
+        let (min, max, mean) = if numeric {
+            let values = (self.count - self.empty) as f64;
+            (
+                self.min_number.as_ref().map(|(_, s)| s.clone()),
+                self.max_number.as_ref().map(|(_, s)| s.clone()),
+                Some(self.sum / values),
+            )
+        } else {
+            (self.min_text.clone(), self.max_text.clone(), None)
+        };
+        let mut frequent: Vec<(&String, &u64)> = self.values.iter().collect();
+        frequent.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        ColumnStats {
+            name: name.to_string(),
+            kind,
+            count: self.count,
+            empty: self.empty,
+            distinct: self.values.len(),
+            exact: self.exact,
+            min,
+            max,
+            mean,
+            top: frequent.into_iter().take(top).map(|(v, n)| (v.clone(), *n)).collect(),
+        }
+    }
+}
+
+/// Accumulates the statistics of every column, one record at a time.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{Stats, Kind};
+/// let headers = vec!["Age".to_string()];
+/// let mut stats = Stats::new(&headers);
+/// for age in &["32", "27", "", "32"] {
+///     stats.add(&[age.to_string()]);
+/// }
+/// let age = &stats.summary(1)[0];
+/// assert_eq!((age.kind, age.empty, age.distinct), (Kind::Int, 1, 2));
+/// assert_eq!(age.mean, Some(91.0 / 3.0));
+/// assert_eq!(age.top, vec![("32".to_string(), 2)]);
+/// ```
+pub struct Stats {
+    headers: Vec<String>,
+    columns: Vec<Column>,
+}
+
+impl Stats {
+    pub fn new(headers: &[String]) -> Stats {
+        let columns = headers.iter().map(|_| Column { exact: true, ..Column::default() }).collect();
+        Stats { headers: headers.to_vec(), columns }
+    }
+
+    pub fn add(&mut self, record: &[String]) {
+        for (column, value) in self.columns.iter_mut().zip(record) {
+            column.add(value);
+        }
+    }
+
+    /// The statistics so far, with the `top` most frequent values.
+    pub fn summary(&self, top: usize) -> Vec<ColumnStats> {
+        self.columns
+            .iter()
+            .zip(&self.headers)
+            .map(|(column, name)| column.summary(name, top))
+            .collect()
+    }
+}
+
+/// Computes the statistics of every column of `reader` in one pass.
+pub fn column_stats<S: Source>(mut reader: S, top: usize) -> Result<Vec<ColumnStats>, Error> {
+    let mut stats = Stats::new(&reader.headers()?);
+    while let Some(record) = reader.read_record()? {
+        stats.add(&record);
+    }
+    Ok(stats.summary(top))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{column_stats, Kind};
+    use crate::core::parse::parse_records;
+    use crate::core::read::open_csv;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_kinds() {
+        let data = "i,f,b,d,t,e\n1,1.5,true,2024-02-29,x,\n-2,3,FALSE,2023-12-01,2,\n";
+        let stats = column_stats(parse_records(data), 3).unwrap();
+        let kinds: Vec<Kind> = stats.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, [Kind::Int, Kind::Float, Kind::Bool, Kind::Date, Kind::Text, Kind::Empty]);
+        assert_eq!((stats[1].min.as_deref(), stats[1].max.as_deref()), (Some("1.5"), Some("3")));
+        assert_eq!(stats[3].max.as_deref(), Some("2024-02-29"));
+        assert_eq!(stats[5].empty, 2);
+        let stats = column_stats(parse_records("d\n2023-02-29\nNaN\n"), 3).unwrap();
+        assert_eq!(stats[0].kind, Kind::Text);
+    }
+
+    #[test]
+    fn test_challenge_stats() {
+        let reader = open_csv(PathBuf::from("./input/challenge.csv")).unwrap();
+        let stats = column_stats(reader, 2).unwrap();
+        let age = stats.iter().find(|s| s.name == "Age").unwrap();
+        assert_eq!(age.kind, Kind::Int);
+        assert_eq!(age.empty, 0);
+        assert!(age.mean.is_some());
+        let record = age.to_record();
+        assert_eq!(record[..3], ["Age", "int", &age.count.to_string()]);
+    }
+}
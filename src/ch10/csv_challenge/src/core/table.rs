@@ -0,0 +1,235 @@
+use super::{Error, PathBuf};
+use super::dialect::Dialect;
+use super::format::Output;
+use super::parse::{parse_records, Reader};
+use super::read::{create_csv, open_csv};
+use super::record::{to_record, Record};
+use super::transform::{position, Transform};
+use crate::err::Position;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io::BufRead};
+
+/// A whole CSV file in memory: a header and records of the same width.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::Table;
+/// use serde::{Deserialize, Serialize};
+/// #[derive(Deserialize, Serialize)]
+/// struct Person { name: String, age: u32 }
+/// let table = Table::parse("name,age\nJohn,32\n").unwrap();
+/// assert_eq!(table.get(0).unwrap().parse::<u32>("age").unwrap(), 32);
+/// let mut people: Vec<Person> = table.deserialize().unwrap();
+/// people[0].age += 1;
+/// let table = Table::from_rows(&people).unwrap();
+/// assert_eq!(table.to_string(), "name,age\nJohn,33\n");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Table {
+    headers: Vec<String>,
+    records: Vec<Vec<String>>,
+    dialect: Dialect,
+    synthetic: bool,
+}
+
+impl Table {
+    pub fn new<S: AsRef<str>>(headers: &[S]) -> Table {
+        Table {
+            headers: headers.iter().map(|h| h.as_ref().to_string()).collect(),
+            ..Table::default()
+        }
+    }
+
+    /// Reads every record of `reader`, keeping its dialect for writing.
+    pub fn from_reader<R: BufRead>(mut reader: Reader<R>) -> Result<Table, Error> {
+        let headers = reader.headers()?;
+        let dialect = reader.dialect().clone();
+        let synthetic = reader.synthetic_headers();
+        let records = reader.collect::<Result<_, _>>()?;
+        Ok(Table { headers, records, dialect, synthetic })
+    }
+
+    pub fn parse(data: &str) -> Result<Table, Error> {
+        Table::from_reader(parse_records(data))
+    }
+
+    pub fn load(csv_file: PathBuf) -> Result<Table, Error> {
+        Table::from_reader(open_csv(csv_file)?)
+    }
+
+    /// Builds a table from serializable rows, the header coming from the
+    /// field names of the first row.
+    pub fn from_rows<T: Serialize>(rows: &[T]) -> Result<Table, Error> {
+        let mut table = Table::default();
+        for row in rows {
+            table.push_row(row)?;
+        }
+        Ok(table)
+    }
+
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn dialect(&self) -> &Dialect {
+        &self.dialect
+    }
+
+    pub fn headers(&self) -> &[String] {
+        &self.headers
+    }
+
+    /// The index of `column`, given by name or as `#N` / `$N`.
+    pub fn column(&self, column: &str) -> Result<usize, Error> {
+        position(&self.headers, column)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Record<'_>> {
+        self.records.get(index).map(|fields| Record::new(&self.headers, fields))
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = Record<'_>> {
+        self.records.iter().map(move |fields| Record::new(&self.headers, fields))
+    }
+
+    /// Appends a record, which must be as wide as the header.
+    pub fn push<S: AsRef<str>>(&mut self, fields: &[S]) -> Result<(), Error> {
+        if fields.len() != self.headers.len() {
+            return Err(Error::Ragged { expected: self.headers.len(), found: fields.len() });
+        }
+        self.records.push(fields.iter().map(|f| f.as_ref().to_string()).collect());
+        Ok(())
+    }
+
+    /// Appends a serializable row. Named fields are placed by header, and
+    /// give an empty table its header; tuples are placed by position.
+    pub fn push_row<T: Serialize + ?Sized>(&mut self, row: &T) -> Result<(), Error> {
+        let (names, fields) = to_record(row)?;
+        if names.is_empty() || names == self.headers {
+            return self.push(&fields);
+        }
+        if self.headers.is_empty() && self.records.is_empty() {
+            self.headers = names;
+            return self.push(&fields);
+        }
+        let mut record = Vec::with_capacity(self.headers.len());
+        for header in &self.headers {
+            match names.iter().position(|name| name == header) {
+                Some(index) => record.push(fields[index].clone()),
+                None => Err(Error::Data(format!("row has no field `{}`", header)))?,
+            }
+        }
+        self.push(&record)
+    }
+
+    /// Reads every record into `T`, see `Record::deserialize`.
+    pub fn deserialize<'a, T: Deserialize<'a>>(&'a self) -> Result<Vec<T>, Error> {
+        self.records()
+            .map(|record| record.deserialize().map_err(|e| e.at(locate(&self.dialect, record.fields()))))
+            .collect()
+    }
+
+    /// Runs every record through `transform`, see `transform_stream`.
+    pub fn transform<T: Transform + ?Sized>(self, transform: &mut T) -> Result<Table, Error> {
+        let headers = transform.headers(&self.headers)?;
+        let mut records = Vec::with_capacity(self.records.len());
+        for record in self.records {
+            let position = locate(&self.dialect, &record);
+            if let Some(record) = transform.apply(record).map_err(|e| e.at(position))? {
+                records.push(record);
+            }
+        }
+        Ok(Table { headers, records, ..self })
+    }
+
+    /// Writes the table to `output`, e.g. a `Writer` or `JsonWriter`.
+    pub fn write<O: Output + ?Sized>(&self, output: &mut O) -> Result<(), Error> {
+        output.write_headers(&self.headers, self.synthetic)?;
+        for record in &self.records {
+            output.write_record(record)?;
+        }
+        output.finish()
+    }
+
+    /// Writes the table to `filename` in its dialect.
+    pub fn save(&self, filename: &str) -> Result<(), Error> {
+        let mut writer = create_csv(filename)?.with_dialect(self.dialect.clone());
+        self.write(&mut writer)
+    }
+}
+
+/// A table has no line numbers, so errors show the record itself.
+fn locate(dialect: &Dialect, record: &[String]) -> Position {
+    let mut line = String::new();
+    dialect.format_record(&mut line, record);
+    let line = line.trim_end_matches(['\r', '\n']).to_string();
+    Position { record: Some(line), ..Position::default() }
+}
+
+/// Formats the table as CSV text in its dialect.
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut out = String::new();
+        if !self.synthetic {
+            self.dialect.format_record(&mut out, &self.headers);
+        }
+        for record in &self.records {
+            self.dialect.format_record(&mut out, record);
+        }
+        f.write_str(&out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Table;
+    use crate::core::transform::Set;
+    use serde::{Deserialize, Serialize};
+    use std::path::PathBuf;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct City {
+        #[serde(rename = "City")]
+        city: String,
+        #[serde(rename = "Code")]
+        code: String,
+    }
+
+    #[test]
+    fn test_load_and_deserialize() {
+        let table = Table::load(PathBuf::from("./input/city_codes.csv")).unwrap();
+        assert_eq!(table.headers(), ["City", "Code"]);
+        assert_eq!(table.column("Code").unwrap(), 1);
+        assert_eq!(table.get(0).unwrap().get("Code").unwrap(), "TYO");
+        let cities: Vec<City> = table.deserialize().unwrap();
+        assert_eq!(cities.len(), table.len());
+        assert_eq!(Table::from_rows(&cities).unwrap().to_string(), table.to_string());
+    }
+
+    #[test]
+    fn test_push_and_transform() {
+        let mut table = Table::new(&["Code", "City"]);
+        table.push(&["TYO", "Tokyo"]).unwrap();
+        assert!(table.push(&["CBR"]).is_err());
+        table.push_row(&City { city: "Paris".into(), code: "PAR".into() }).unwrap();
+        table.push_row(&("DUB", "Dublin")).unwrap();
+        let table = table.transform(&mut Set::new("City", "Beijing")).unwrap();
+        assert_eq!(table.to_string(), "Code,City\nTYO,Beijing\nPAR,Beijing\nDUB,Beijing\n");
+    }
+
+    #[test]
+    fn test_deserialize_error_position() {
+        let table = Table::parse("Name,Age\nJohn,old\n").unwrap();
+        let e = table.deserialize::<(String, u32)>().unwrap_err();
+        assert_eq!(e.to_string(), "<input>: column `Age`: cannot parse `old` as an unsigned integer\n    John,old");
+    }
+}
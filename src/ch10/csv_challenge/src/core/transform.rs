@@ -0,0 +1,289 @@
+use super::Error;
+use super::replace::Replacement;
+
+/// A step applied to every record of a stream.
+///
+/// `headers` is called once with the incoming header before any record,
+/// so column names can be resolved to positions up front, and returns the
+/// header of the outgoing stream. `apply` only borrows the step, so a
+/// `Sync` one can be shared by worker threads.
+pub trait Transform {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error>;
+    /// Transforms one record, `None` drops it from the output.
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error>;
+}
+
+/// Resolves a column by name, or by index when no header matches:
+/// `#N` counts from zero and `$N` from one.
+pub(crate) fn position(headers: &[String], column: &str) -> Result<usize, Error> {
+    if let Some(index) = headers.iter().position(|e| e == column) {
+        return Ok(index);
+    }
+    let index = if let Some(n) = column.strip_prefix('#') {
+        n.parse::<usize>().ok()
+    } else if let Some(n) = column.strip_prefix('$') {
+        n.parse::<usize>().ok().and_then(|n| n.checked_sub(1))
+    } else {
+        None
+    };
+    match index {
+        Some(index) if index < headers.len() => Ok(index),
+        _ => Err(Error::UnknownColumn(column.to_string())),
+    }
+}
+
+pub(crate) fn field(record: &[String], index: usize) -> Result<&String, Error> {
+    match record.get(index) {
+        Some(field) => Ok(field),
+        None => Err(Error::Ragged { expected: index + 1, found: record.len() }),
+    }
+}
+
+/// Transforms applied one after another, itself a `Transform`.
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{Pipeline, Set, Rename, Transform};
+/// let mut pipeline = Pipeline::new()
+///     .then(Set::new("City", "Beijing"))
+///     .then(Rename::new("City", "Town"));
+/// let headers = vec!["Name".to_string(), "City".to_string()];
+/// assert_eq!(pipeline.headers(&headers).unwrap(), vec!["Name", "Town"]);
+/// let record = vec!["John".to_string(), "Tokyo".to_string()];
+/// assert_eq!(pipeline.apply(record).unwrap().unwrap(), vec!["John", "Beijing"]);
+/// ```
+#[derive(Default)]
+pub struct Pipeline {
+    steps: Vec<Box<dyn Transform + Send + Sync>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline { steps: Vec::new() }
+    }
+
+    pub fn then<T: Transform + Send + Sync + 'static>(mut self, step: T) -> Self {
+        self.push(step);
+        self
+    }
+
+    pub fn push<T: Transform + Send + Sync + 'static>(&mut self, step: T) {
+        self.steps.push(Box::new(step));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl Transform for Pipeline {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        let mut headers = headers.to_vec();
+        for step in &mut self.steps {
+            headers = step.headers(&headers)?;
+        }
+        Ok(headers)
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        let mut record = record;
+        for step in &self.steps {
+            record = match step.apply(record)? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(record))
+    }
+}
+
+/// Rewrites every value of a column, with a constant by default.
+pub struct Set {
+    column: String,
+    replacement: Replacement,
+    index: usize,
+}
+
+impl Set {
+    pub fn new(column: &str, value: &str) -> Self {
+        Set::with(column, Replacement::Value(value.to_string()))
+    }
+
+    pub fn with(column: &str, replacement: Replacement) -> Self {
+        Set { column: column.to_string(), replacement, index: 0 }
+    }
+}
+
+impl Transform for Set {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        self.index = position(headers, &self.column)?;
+        self.replacement.resolve(headers)?;
+        Ok(headers.to_vec())
+    }
+
+    fn apply(&self, mut record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        record[self.index] = self.replacement.apply(&record, self.index)?;
+        Ok(Some(record))
+    }
+}
+
+/// Renames a column, leaving its values untouched.
+pub struct Rename {
+    from: String,
+    to: String,
+}
+
+impl Rename {
+    pub fn new(from: &str, to: &str) -> Self {
+        Rename { from: from.to_string(), to: to.to_string() }
+    }
+}
+
+impl Transform for Rename {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        let index = position(headers, &self.from)?;
+        let mut headers = headers.to_vec();
+        headers[index] = self.to.clone();
+        Ok(headers)
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        Ok(Some(record))
+    }
+}
+
+/// Keeps only the given columns, in the given order.
+pub struct Select {
+    columns: Vec<String>,
+    indices: Vec<usize>,
+}
+
+impl Select {
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        let columns = columns.iter().map(|c| c.as_ref().to_string()).collect();
+        Select { columns, indices: Vec::new() }
+    }
+}
+
+pub(crate) fn positions(headers: &[String], columns: &[String]) -> Result<Vec<usize>, Error> {
+    columns.iter().map(|column| position(headers, column)).collect()
+}
+
+fn pick(record: &[String], indices: &[usize]) -> Result<Vec<String>, Error> {
+    indices.iter().map(|&i| field(record, i).cloned()).collect()
+}
+
+impl Transform for Select {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        self.indices = positions(headers, &self.columns)?;
+        pick(headers, &self.indices)
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        Ok(Some(pick(&record, &self.indices)?))
+    }
+}
+
+/// Removes the given columns.
+pub struct Exclude {
+    columns: Vec<String>,
+    keep: Vec<bool>,
+}
+
+impl Exclude {
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        let columns = columns.iter().map(|c| c.as_ref().to_string()).collect();
+        Exclude { columns, keep: Vec::new() }
+    }
+}
+
+impl Transform for Exclude {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        self.keep = vec![true; headers.len()];
+        for column in &self.columns {
+            self.keep[position(headers, column)?] = false;
+        }
+        Ok(self.apply(headers.to_vec())?.unwrap_or_default())
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        let kept = record
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| self.keep.get(i).cloned().unwrap_or(true))
+            .map(|(_, field)| field)
+            .collect();
+        Ok(Some(kept))
+    }
+}
+
+/// Moves the given columns to the front, keeping the others after them
+/// in their original order.
+pub struct Reorder {
+    columns: Vec<String>,
+    indices: Vec<usize>,
+}
+
+impl Reorder {
+    pub fn new<S: AsRef<str>>(columns: &[S]) -> Self {
+        let columns = columns.iter().map(|c| c.as_ref().to_string()).collect();
+        Reorder { columns, indices: Vec::new() }
+    }
+}
+
+impl Transform for Reorder {
+    fn headers(&mut self, headers: &[String]) -> Result<Vec<String>, Error> {
+        let front = positions(headers, &self.columns)?;
+        let rest = (0..headers.len()).filter(|i| !front.contains(i));
+        self.indices = front.iter().cloned().chain(rest).collect();
+        pick(headers, &self.indices)
+    }
+
+    fn apply(&self, record: Vec<String>) -> Result<Option<Vec<String>>, Error> {
+        Ok(Some(pick(&record, &self.indices)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Exclude, Pipeline, Rename, Reorder, Select, Set, Transform};
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_pipeline_in_one_pass() {
+        let mut pipeline = Pipeline::new()
+            .then(Set::new("City", "Beijing"))
+            .then(Set::new("Age", "0"))
+            .then(Rename::new("Last", "Surname"))
+            .then(Exclude::new(&["Age"]))
+            .then(Reorder::new(&["City"]));
+        let headers = pipeline.headers(&strings(&["First", "Last", "Age", "City"])).unwrap();
+        assert_eq!(headers, strings(&["City", "First", "Surname"]));
+        let record = pipeline.apply(strings(&["John", "Doe", "32", "Tokyo"])).unwrap();
+        assert_eq!(record, Some(strings(&["Beijing", "John", "Doe"])));
+    }
+
+    #[test]
+    fn test_select_and_unknown_column() {
+        let headers = strings(&["a", "b", "c"]);
+        let mut select = Select::new(&["c", "a"]);
+        assert_eq!(select.headers(&headers).unwrap(), strings(&["c", "a"]));
+        assert_eq!(select.apply(strings(&["1", "2", "3"])).unwrap(), Some(strings(&["3", "1"])));
+        assert!(Select::new(&["d"]).headers(&headers).is_err());
+        assert!(Set::new("d", "x").headers(&headers).is_err());
+    }
+
+    #[test]
+    fn test_columns_by_index() {
+        let headers = strings(&["a", "b", "c"]);
+        let mut select = Select::new(&["#2", "$1"]);
+        assert_eq!(select.headers(&headers).unwrap(), strings(&["c", "a"]));
+        let mut reorder = Reorder::new(&["$2"]);
+        assert_eq!(reorder.headers(&headers).unwrap(), strings(&["b", "a", "c"]));
+        assert!(Select::new(&["#3"]).headers(&headers).is_err());
+        assert!(Select::new(&["$0"]).headers(&headers).is_err());
+    }
+}
@@ -0,0 +1,317 @@
+use super::{Error, PathBuf};
+use super::parse::Source;
+use super::stats::Kind;
+use crate::err::Position;
+use regex::Regex;
+use serde::Deserialize;
+use std::{fmt, fs};
+
+/// The schema file as written, see `Schema`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaFile {
+    #[serde(default)]
+    strict: bool,
+    #[serde(default, rename = "column")]
+    columns: Vec<ColumnFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ColumnFile {
+    name: String,
+    #[serde(default = "required")]
+    required: bool,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    pattern: Option<String>,
+    #[serde(rename = "enum")]
+    values: Option<Vec<String>>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+fn required() -> bool {
+    true
+}
+
+/// The checks on one column.
+struct Rule {
+    name: String,
+    required: bool,
+    kind: Option<Kind>,
+    pattern: Option<Regex>,
+    values: Option<Vec<String>>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Rule {
+    /// What is wrong with `value`, if anything.
+    fn check(&self, value: &str) -> Option<String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return if self.required { Some("value is required".to_string()) } else { None };
+        }
+        if let Some(kind) = self.kind {
+            if !kind.admits(value) {
+                return Some(format!("`{}` is not {}", value, kind));
+            }
+        }
+        if let Some(values) = &self.values {
+            if !values.iter().any(|v| v == value) {
+                return Some(format!("`{}` is not one of {}", value, values.join(", ")));
+            }
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(value) {
+                return Some(format!("`{}` doesn't match `{}`", value, pattern));
+            }
+        }
+        if self.min.is_some() || self.max.is_some() {
+            let number = match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => number,
+                _ => return Some(format!("`{}` is not a number", value)),
+            };
+            if let Some(min) = self.min.filter(|&min| number < min) {
+                return Some(format!("`{}` is below the minimum {}", value, min));
+            }
+            if let Some(max) = self.max.filter(|&max| number > max) {
+                return Some(format!("`{}` is above the maximum {}", value, max));
+            }
+        }
+        None
+    }
+}
+
+/// A cell, or a header, that breaks the schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    /// The record of the cell, or the header for missing and unexpected
+    /// columns.
+    pub position: Position,
+    pub column: String,
+    pub message: String,
+}
+
+impl Violation {
+    pub const HEADERS: [&'static str; 4] = ["file", "line", "column", "message"];
+
+    /// The violation as a record under `HEADERS`.
+    pub fn to_record(&self) -> Vec<String> {
+        vec![
+            self.position.file.clone().unwrap_or_default(),
+            self.position.line.to_string(),
+            self.column.clone(),
+            self.message.clone(),
+        ]
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: column `{}`: {}", self.position, self.column, self.message)
+    }
+}
+
+/// Column names, types and constraints an input must meet, read from a
+/// TOML file:
+///
+/// ```toml
+/// # Columns the schema doesn't list are violations too.
+/// strict = true
+///
+/// [[column]]
+/// name = "Age"
+/// type = "int"        # bool, int, float, date or text
+/// min = 0
+/// max = 200
+///
+/// [[column]]
+/// name = "City"
+/// required = false    # the column may be left out, and its values empty
+/// pattern = "^[A-Z]"  # matched anywhere unless anchored
+///
+/// [[column]]
+/// name = "Species"
+/// enum = ["Human", "Orc", "Unknown"]
+/// ```
+///
+/// # Usage:
+/// ```
+/// use csv_challenge::{parse_records, Schema};
+/// let schema = Schema::parse("[[column]]\nname = \"Age\"\ntype = \"int\"\nmin = 0\n").unwrap();
+/// let mut report = Vec::new();
+/// let found = schema.validate(parse_records("Age\n32\nold\n-1\n"), |v| Ok(report.push(v.to_string())));
+/// assert_eq!(found.unwrap(), 2);
+/// assert_eq!(report, ["<input>:3: column `Age`: `old` is not int",
+///                     "<input>:4: column `Age`: `-1` is below the minimum 0"]);
+/// ```
+pub struct Schema {
+    strict: bool,
+    rules: Vec<Rule>,
+}
+
+impl Schema {
+    pub fn parse(text: &str) -> Result<Schema, Error> {
+        let file: SchemaFile =
+            toml::from_str(text).map_err(|e| Error::Data(format!("invalid schema: {}", e)))?;
+        let mut rules = Vec::with_capacity(file.columns.len());
+        for column in file.columns {
+            let invalid = |message: &str| Error::Data(format!("invalid schema: column `{}`: {}", column.name, message));
+            let kind = match &column.kind {
+                Some(kind) => Some(kind.parse::<Kind>().map_err(invalid)?),
+                None => None,
+            };
+            let pattern = match &column.pattern {
+                Some(pattern) => Some(Regex::new(pattern)?),
+                None => None,
+            };
+            if let (Some(min), Some(max)) = (column.min, column.max) {
+                if min > max {
+                    return Err(invalid("min is above max"));
+                }
+            }
+            if rules.iter().any(|rule: &Rule| rule.name == column.name) {
+                return Err(invalid("listed twice"));
+            }
+            rules.push(Rule {
+                name: column.name,
+                required: column.required,
+                kind,
+                pattern,
+                values: column.values,
+                min: column.min,
+                max: column.max,
+            });
+        }
+        Ok(Schema { strict: file.strict, rules })
+    }
+
+    pub fn load(path: PathBuf) -> Result<Schema, Error> {
+        let text = fs::read_to_string(&path)?;
+        let position = Position { file: Some(path.to_string_lossy().into_owned()), ..Position::default() };
+        Schema::parse(&text).map_err(|e| e.at(position))
+    }
+
+    /// Checks every cell of `reader`, passing each violation to `report`,
+    /// and returns how many there were.
+    pub fn validate<S, F>(&self, mut reader: S, mut report: F) -> Result<u64, Error>
+    where
+        S: Source,
+        F: FnMut(Violation) -> Result<(), Error>,
+    {
+        let headers = reader.headers()?;
+        let header = Position {
+            file: reader.name().map(String::from),
+            line: if reader.synthetic_headers() { 0 } else { reader.record_position().line },
+            ..Position::default()
+        };
+        let mut violations = 0;
+        let mut violation = |position: &Position, column: &str, message: String| {
+            violations += 1;
+            report(Violation { position: position.clone(), column: column.to_string(), message })
+        };
+        let mut columns = Vec::new();
+        for rule in &self.rules {
+            match headers.iter().position(|h| h == &rule.name) {
+                Some(index) => columns.push((index, rule)),
+                None if rule.required => violation(&header, &rule.name, "column is missing".to_string())?,
+                None => {}
+            }
+        }
+        if self.strict {
+            for name in headers.iter().filter(|&h| !self.rules.iter().any(|rule| &rule.name == h)) {
+                violation(&header, name, "column is not in the schema".to_string())?;
+            }
+        }
+        while let Some(record) = reader.read_record()? {
+            let mut position = None;
+            for &(index, rule) in &columns {
+                let value = record.get(index).map(String::as_str).unwrap_or_default();
+                if let Some(message) = rule.check(value) {
+                    let position = position.get_or_insert_with(|| {
+                        Position { record: None, ..reader.record_position() }
+                    });
+                    violation(position, &rule.name, message)?;
+                }
+            }
+        }
+        Ok(violations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Schema;
+    use crate::core::parse::parse_records;
+    use crate::core::read::open_csv;
+    use std::path::PathBuf;
+
+    fn validate(schema: &str, data: &str) -> Vec<String> {
+        let schema = Schema::parse(schema).unwrap();
+        let mut report = Vec::new();
+        let found = schema.validate(parse_records(data), |v| {
+            report.push(v.to_string());
+            Ok(())
+        });
+        assert_eq!(found.unwrap(), report.len() as u64);
+        report
+    }
+
+    #[test]
+    fn test_violations() {
+        let schema = r#"
+            strict = true
+            [[column]]
+            name = "Name"
+            pattern = "^[A-Z][a-z]+$"
+            [[column]]
+            name = "Species"
+            enum = ["Human", "Orc"]
+            [[column]]
+            name = "Born"
+            type = "date"
+            required = false
+            [[column]]
+            name = "Age"
+            type = "int"
+            max = 150
+        "#;
+        let data = "Name,Species,Eyes\nJohn,Human,Blue\njohn,Elf,\n,Orc,Red\n";
+        assert_eq!(
+            validate(schema, data),
+            [
+                "<input>:1: column `Age`: column is missing",
+                "<input>:1: column `Eyes`: column is not in the schema",
+                "<input>:3: column `Name`: `john` doesn't match `^[A-Z][a-z]+$`",
+                "<input>:3: column `Species`: `Elf` is not one of Human, Orc",
+                "<input>:4: column `Name`: value is required",
+            ]
+        );
+        let data = "Age,Born\n151,\n1.5,2024-02-30\n 7 , 2024-02-29\n";
+        assert_eq!(
+            validate(schema, data),
+            [
+                "<input>:1: column `Name`: column is missing",
+                "<input>:1: column `Species`: column is missing",
+                "<input>:2: column `Age`: `151` is above the maximum 150",
+                "<input>:3: column `Born`: `2024-02-30` is not date",
+                "<input>:3: column `Age`: `1.5` is not int",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        let invalid = |schema: &str| Schema::parse(schema).err().map(|e| e.to_string()).unwrap_or_default();
+        assert!(invalid("[[column]]\nname = \"A\"\ntype = \"uuid\"\n").contains("type must be one of"));
+        assert!(invalid("[[column]]\nname = \"A\"\nmin = 2\nmax = 1\n").contains("min is above max"));
+        assert!(invalid("[[column]]\nname = \"A\"\nrequird = true\n").contains("unknown field `requird`"));
+        assert!(invalid("[[column]]\nname = \"A\"\npattern = \"(\"\n").contains("regex"));
+
+        let schema = Schema::load(PathBuf::from("./input/challenge.schema.toml")).unwrap();
+        let reader = open_csv(PathBuf::from("./input/challenge.csv")).unwrap();
+        assert_eq!(schema.validate(reader, |_| Ok(())).unwrap(), 0);
+    }
+}
@@ -1,31 +1,67 @@
 use super::*;
+use crate::err::Position;
+use super::parse::{Reader, Source, Writer};
+use super::table::Table;
+use super::format::Output;
+use super::compress::Finish;
+use super::transform::{Set, Transform};
+use super::ragged::RaggedSummary;
+use std::io::BufRead;
+
+/// Runs every record of `reader` through `transform` and writes the
+/// result to `output`, one record at a time, returning what the reader's
+/// `RaggedPolicy` did. Errors are located in the input.
+pub fn transform_stream<S: Source, O: Output + ?Sized, T: Transform + ?Sized>(
+    mut reader: S,
+    output: &mut O,
+    transform: &mut T,
+) -> Result<RaggedSummary, Error> {
+    let headers = transform.headers(&reader.headers()?).map_err(|e| {
+        e.at(Position { file: reader.name().map(String::from), ..Position::default() })
+    })?;
+    output.write_headers(&headers, reader.synthetic_headers())?;
+    while let Some(record) = reader.read_record()? {
+        match transform.apply(record) {
+            Ok(Some(record)) => output.write_record(&record)?,
+            Ok(None) => {}
+            Err(e) => return Err(e.at(reader.record_position())),
+        }
+    }
+    output.finish()?;
+    Ok(reader.ragged_summary())
+}
+
+/// Replaces every value of `column` with `replacement`, one record at a
+/// time, so the whole input never has to be held in memory.
+///
+/// # Usage:
+/// ```ignore
+/// let reader = open_csv(PathBuf::from("./input/challenge.csv")).unwrap();
+/// let mut writer = create_csv("output/test.csv").unwrap();
+/// replace_column_stream(reader, &mut writer, "City", "Beijing").unwrap();
+/// ```
+pub fn replace_column_stream<R: BufRead, W: Finish>(
+    reader: Reader<R>,
+    writer: &mut Writer<W>,
+    column: &str,
+    replacement: &str,
+) -> Result<(), Error> {
+    transform_stream(reader, writer, &mut Set::new(column, replacement))?;
+    Ok(())
+}
+
 pub fn replace_column(data: String, column: &str, replacement: &str) 
     -> Result<String, Error> {
-    let mut lines = data.lines();
-    let headers = lines.next().unwrap();
-    let columns: Vec<&str> = headers.split(',').collect();
-    let column_number = columns.iter().position(|&e| e == column);
-    let column_number = match column_number {
-        Some(column) => column,
-       None => Err("column name doesn’t exist in the input file")?
-   };
-   let mut result = String::with_capacity(data.capacity());
-   result.push_str(&columns.join(","));
-   result.push('\n');
-   for line in lines {
-       let mut records: Vec<&str> = line.split(',').collect();
-       records[column_number] = replacement;
-       result.push_str(&records.join(","));
-       result.push('\n');
-   }
-   Ok(result)
+    let table = Table::parse(&data)?.transform(&mut Set::new(column, replacement))?;
+    Ok(table.to_string())
 }
 
 #[cfg(test)]
 mod test {
     use std::path::PathBuf;
-    use super::read::load_csv;
-    use super::{replace_column};
+    use super::read::{load_csv, open_csv};
+    use super::parse::Writer;
+    use super::{replace_column, replace_column_stream};
 
     #[test]
     fn test_valid_replace_column(){
@@ -42,4 +78,42 @@ mod test {
         let modified_data = replace_column(csv_data, "City2", "Beijing");
         assert!(modified_data.is_err());
     }
+
+    #[test]
+    fn test_replace_quoted_column(){
+        let filename = PathBuf::from("./input/quoted.csv");
+        let csv_data = load_csv(filename).unwrap();
+        let modified_data = replace_column(csv_data, "City", "Beijing").unwrap();
+        let mut lines = modified_data.lines();
+        assert_eq!(lines.next(), Some("Name,City,Note"));
+        assert_eq!(lines.next(), Some("\"Doe, John\",Beijing,\"likes \"\"tea\"\"\""));
+    }
+
+    #[test]
+    fn test_replace_column_without_header(){
+        let reader = open_csv(PathBuf::from("./input/no_header.csv")).unwrap();
+        let mut writer = Writer::new(Vec::new());
+        replace_column_stream(reader.no_header(), &mut writer, "$4", "Beijing").unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(output.lines().next(), Some("John,Doe,32,Beijing,Blue,Human"));
+    }
+
+    #[test]
+    fn test_ragged_row_position(){
+        let data = "a,b\n1,2\n3\n".to_string();
+        let e = replace_column(data, "b", "x").unwrap_err();
+        assert_eq!(e.to_string(), "<input>:3: record has 1 fields, expected 2\n    3");
+        assert_eq!(e.exit_code(), 65);
+    }
+
+    #[test]
+    fn test_replace_column_stream(){
+        let reader = open_csv(PathBuf::from("./input/challenge.csv")).unwrap();
+        let mut writer = Writer::new(Vec::new());
+        replace_column_stream(reader, &mut writer, "City", "Beijing").unwrap();
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let csv_data = load_csv(PathBuf::from("./input/challenge.csv")).unwrap();
+        assert_eq!(output, replace_column(csv_data, "City", "Beijing").unwrap());
+        assert!(output.lines().skip(1).all(|line| line.contains(",Beijing,")));
+    }
 }
\ No newline at end of file
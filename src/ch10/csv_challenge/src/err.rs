@@ -1,16 +1,163 @@
-use std::io;
+use std::{error, fmt, io};
+
+/// Where in the input an error happened.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Position {
+    pub file: Option<String>,
+    /// 1-based line number, 0 when not tied to a line.
+    pub line: u64,
+    /// 1-based character column within the line.
+    pub column: Option<usize>,
+    /// The offending record as it appears in the input.
+    pub record: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
+    Regex(regex::Error),
     Program(&'static str),
+    /// Malformed quoting in the input.
+    Syntax(&'static str),
+    /// A record with `found` fields where `expected` were needed.
+    Ragged { expected: usize, found: usize },
+    /// A field that doesn't hold the expected type.
+    Data(String),
+    /// A column name or index the header doesn't have.
+    UnknownColumn(String),
+    /// Another error, located in the input.
+    At(Position, Box<Error>),
+}
+
+impl Error {
+    /// Attaches `position` unless the error already has one.
+    pub fn at(self, position: Position) -> Error {
+        match self {
+            Error::At(..) => self,
+            e => Error::At(position, Box::new(e)),
+        }
+    }
+
+    /// The error without its position.
+    pub fn kind(&self) -> &Error {
+        match self {
+            Error::At(_, e) => e.kind(),
+            e => e,
+        }
+    }
+
+    pub fn position(&self) -> Option<&Position> {
+        match self {
+            Error::At(position, _) => Some(position),
+            _ => None,
+        }
+    }
+
+    /// The process exit code for this error, following sysexits.h:
+    /// 64 for bad usage, 65 for bad input data, 66 for a missing input,
+    /// 74 for other I/O errors and 70 for the rest.
+    pub fn exit_code(&self) -> i32 {
+        match self.kind() {
+            Error::Regex(_) | Error::UnknownColumn(_) => 64,
+            Error::Syntax(_) | Error::Ragged { .. } | Error::Data(_) => 65,
+            Error::Io(e) if e.kind() == io::ErrorKind::NotFound => 66,
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidData => 65,
+            Error::Io(_) => 74,
+            _ => 70,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file.as_deref().unwrap_or("<input>"))?;
+        if self.line > 0 {
+            write!(f, ":{}", self.line)?;
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{}", column)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Regex(e) => write!(f, "{}", e),
+            Error::Program(message) | Error::Syntax(message) => write!(f, "{}", message),
+            Error::Ragged { expected, found } => {
+                write!(f, "record has {} fields, expected {}", found, expected)
+            }
+            Error::Data(message) => write!(f, "{}", message),
+            Error::UnknownColumn(column) => {
+                write!(f, "column `{}` doesn’t exist in the input file", column)
+            }
+            Error::At(position, e) => {
+                write!(f, "{}: {}", position, e)?;
+                match &position.record {
+                    Some(record) => write!(f, "\n    {}", record),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
 }
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Regex(e) => Some(e),
+            Error::At(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::Io(e)
    }
 }
+impl From<regex::Error> for Error {
+    fn from(e: regex::Error) -> Error {
+        Error::Regex(e)
+    }
+}
 impl From<&'static str> for Error {
    fn from(e: &'static str) -> Error {
        Error::Program(e)
    }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Position};
+    use std::io;
+
+    #[test]
+    fn test_display_with_position() {
+        let position = Position {
+            file: Some("input/bad.csv".to_string()),
+            line: 3,
+            column: Some(7),
+            record: Some("\"abc\"d,e".to_string()),
+        };
+        let e = Error::Syntax("unexpected character after closing quote").at(position);
+        assert_eq!(
+            e.to_string(),
+            "input/bad.csv:3:7: unexpected character after closing quote\n    \"abc\"d,e"
+        );
+        assert_eq!(e.exit_code(), 65);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(Error::UnknownColumn("City2".to_string()).exit_code(), 64);
+        let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert_eq!(Error::from(missing).exit_code(), 66);
+        assert_eq!(Error::from("input file missing").exit_code(), 70);
+    }
+}
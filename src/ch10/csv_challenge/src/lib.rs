@@ -12,8 +12,32 @@ mod opt;
 mod err;
 mod core;
 // Re-exporting
-pub use self::opt::Opt;
+pub use self::opt::{Opt, ReadOpt, WriteOpt, SpillOpt, Command, OutputEncoding};
+pub use self::err::{Error, Position};
 pub use self::core::{
-    read::{load_csv, write_csv},
-    write::replace_column,
+    read::{load_csv, write_csv, open_csv, create_csv, open_input, create_output, create_formatted, create_encoded, output_stream, input_stream, open_json, InPlace},
+    parse::{parse_records, format_record, Reader, Writer, Source},
+    dialect::{Dialect, Escape, Terminator, Trim},
+    ragged::{RaggedPolicy, RaggedSummary},
+    record::Record,
+    table::Table,
+    transform::{Transform, Pipeline, Set, Rename, Select, Exclude, Reorder},
+    filter::{Filter, Op, Where, When},
+    replace::{Replacement, Template},
+    format::{Format, Output, JsonWriter, MarkdownWriter},
+    json::JsonReader,
+    sort::{Sort, SortKey, Dedup, DEFAULT_MEMORY_LIMIT},
+    join::{Join, JoinKind, Duplicates},
+    group::{GroupBy, Aggregate, Function},
+    validate::{Schema, Violation},
+    diff::{Diff, Change, Cell, DiffSummary},
+    parallel::{transform_parallel, CHUNK_SIZE},
+    encoding::{Charset, EncodeWriter, SNIFF_SIZE},
+    compress::{Compression, CompressWriter, Finish},
+    expr::{Expr, Value, Compute},
+    plan::Plan,
+    split::{Split, SplitBy},
+    cat::Cat,
+    stats::{column_stats, ColumnStats, Kind, Stats, DISTINCT_LIMIT},
+    write::{replace_column, replace_column_stream, transform_stream},
 };
\ No newline at end of file
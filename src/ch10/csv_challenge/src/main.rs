@@ -1,44 +1,286 @@
 use structopt::StructOpt;
 use csv_challenge::{
     Opt,
-    {load_csv, write_csv},
-    replace_column,
+    Error,
+    Command,
+    {create_encoded, InPlace},
+    Format,
+    column_stats,
+    ColumnStats,
+    {Sort, SortKey, Dedup},
+    Join,
+    {GroupBy, Aggregate},
+    {Schema, Violation},
+    {Diff, Change},
+    Plan,
+    {Split, SplitBy, Cat},
+    {WriteOpt, Output, Dialect},
+    output_stream,
+    Writer,
+    RaggedSummary,
+    transform_stream,
+    {transform_parallel, Pipeline},
+    Charset,
+    {Compression, Finish},
 };
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::Path;
 use std::process;
 fn main() {
    let opt = Opt::from_args();
-   let filename = PathBuf::from(opt.input);
-   let csv_data = match load_csv(filename) {
-       Ok(fname) => { fname },
+   let result = match &opt.command {
+       Some(command) => run_command(command),
+       None => run(&opt),
+   };
+   match result {
+       Ok(Some(output)) => {
+           eprintln!("write success! {}", output);
+       },
+       Ok(None) => {},
        Err(e) => {
-           println!("main error: {:?}", e);
-           process::exit(1);
+           eprintln!("main error: {}", e);
+           process::exit(e.exit_code());
        }
-   };
+   }
+}
+
+/// Findings of a command, as lines of text or, with `--output-format`,
+/// as records.
+enum Report {
+   Text(Box<dyn Finish>),
+   Records(Box<dyn Output>),
+}
 
-    let modified_data = match 
-        replace_column(csv_data,  &opt.column_name, &opt.replacement)
-        {
-            Ok(data) => { data },
-            Err(e) => {
-               println!("main error: {:?}", e);
-               process::exit(1);
+impl Report {
+   fn create(write: &WriteOpt, headers: &[&str], dialect: Dialect) -> Result<Report, Error> {
+       match write.output_format {
+           Some(format) => {
+               let mut writer = write.create(format, dialect)?;
+               let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+               writer.write_headers(&headers, false)?;
+               Ok(Report::Records(writer))
+           }
+           None => {
+               let output = output_stream(write.file())?;
+               match write.output_encoding {
+                   Some(charset) => Ok(Report::Text(Box::new(charset.encoder(output)))),
+                   None => Ok(Report::Text(output)),
+               }
            }
-       };   
+       }
+   }
 
-   let output_file = &opt.output
-       .unwrap_or("output/output.csv".to_string());
+   fn add<T: Display>(&mut self, finding: &T, records: &[Vec<String>]) -> Result<(), Error> {
+       match self {
+           Report::Text(output) => writeln!(output, "{}", finding)?,
+           Report::Records(writer) => {
+               for record in records {
+                   writer.write_record(record)?;
+               }
+           }
+       }
+       Ok(())
+   }
 
-   match write_csv(&modified_data, &output_file)  {
-       Ok(_) => {
-           println!("write success!");
-       },
-       Err(e) => {
-           println!("main error: {:?}", e);
-           process::exit(1);
+   fn finish(&mut self) -> Result<(), Error> {
+       match self {
+           Report::Text(output) => Ok(output.finish()?),
+           Report::Records(writer) => writer.finish(),
+       }
+   }
+}
+
+fn report(summary: RaggedSummary) {
+   if !summary.is_empty() {
+       eprintln!("{}", summary);
+   }
+}
+
+/// Returns the name of the file written, `None` for stdout.
+fn run(opt: &Opt) -> Result<Option<String>, Error> {
+   let mut pipeline = opt.pipeline()?;
+   let input = opt.input()?;
+   let input_format = opt.read.format(input)?;
+   let output = opt.output_file.as_ref().or(opt.output.as_ref());
+
+   if opt.in_place {
+       if input == "-" || output.is_some() {
+           Err("--in-place needs an input file and no output")?
        }
+       if opt.output_format != Format::Csv || matches!(input_format, Format::Json | Format::Ndjson) {
+           Err("--in-place only rewrites CSV as CSV")?
+       }
+       // Compressed files are rewritten compressed the same way.
+       let compression = Compression::detect(Path::new(input))?;
+       let (in_place, file) = InPlace::create(Path::new(input))?;
+       report(transform(opt, input, &mut pipeline, |dialect, charset| {
+           let stream = compression.compress(file)?;
+           let charset = opt.output_charset(charset);
+           if charset == Charset::default() {
+               return Ok(Box::new(Writer::new(stream).with_dialect(dialect)));
+           }
+           Ok(Box::new(Writer::new(charset.encoder(stream)).with_dialect(dialect)))
+       })?);
+       in_place.commit(opt.backup)?;
+       return Ok(Some(input.to_string()));
    }
+   if opt.backup {
+       Err("--backup only applies to --in-place")?
+   }
+
+   report(transform(opt, input, &mut pipeline, |dialect, charset| {
+       create_encoded(output.map(|o| o.as_str()), opt.output_format, dialect, opt.output_charset(charset))
+   })?);
+   Ok(output.filter(|&o| o != "-").cloned())
+}
+
+/// Runs `pipeline` over `input` into the output `create` makes for the
+/// input dialect and charset, on `--jobs` threads unless the input is JSON.
+fn transform<F>(opt: &Opt, input: &str, pipeline: &mut Pipeline, create: F) -> Result<RaggedSummary, Error>
+where
+   F: FnOnce(Dialect, Charset) -> Result<Box<dyn Output>, Error>,
+{
+   let jobs = opt.jobs();
+   if jobs > 1 && !matches!(opt.read.format(input)?, Format::Json | Format::Ndjson) {
+       let (reader, charset) = opt.read.open_csv(input)?;
+       let mut output = create(reader.dialect().clone(), charset)?;
+       return transform_parallel(reader, output.as_mut(), pipeline, jobs);
+   }
+   let (reader, dialect, charset) = opt.read.open_encoded(input)?;
+   let mut output = create(dialect, charset)?;
+   transform_stream(reader, output.as_mut(), pipeline)
+}
 
-   
-}
\ No newline at end of file
+fn run_command(command: &Command) -> Result<Option<String>, Error> {
+   match command {
+       Command::Stats { input, top, write, read } => {
+           let (reader, dialect) = read.open(input)?;
+           let stats = column_stats(reader, *top)?;
+           let mut writer = write.create(Format::Markdown, dialect)?;
+           let headers: Vec<String> = ColumnStats::HEADERS.iter().map(|h| h.to_string()).collect();
+           writer.write_headers(&headers, false)?;
+           for column in &stats {
+               writer.write_record(&column.to_record())?;
+           }
+           writer.finish()?;
+           Ok(write.file().map(String::from))
+       }
+       Command::Sort { input, by, spill, write, read } => {
+           let keys = by.iter().map(|key| SortKey::parse(key)).collect::<Result<_, _>>()?;
+           let (reader, dialect) = read.open(input)?;
+           let mut writer = write.create(Format::Csv, dialect)?;
+           Sort::new(keys)
+               .memory_limit(spill.memory_limit())
+               .temp_dir(spill.temp_dir())
+               .sort(reader, writer.as_mut())?;
+           Ok(write.file().map(String::from))
+       }
+       Command::Dedup { input, key, spill, write, read } => {
+           let (reader, dialect) = read.open(input)?;
+           let mut writer = write.create(Format::Csv, dialect)?;
+           let removed = Dedup::new(key)
+               .memory_limit(spill.memory_limit())
+               .temp_dir(spill.temp_dir())
+               .dedup(reader, writer.as_mut())?;
+           eprintln!("{} duplicate rows removed", removed);
+           Ok(write.file().map(String::from))
+       }
+       Command::Join { left, right, on, kind, duplicates, suffix, spill, write, read } => {
+           let on: Vec<(String, String)> = on.iter().map(|key| Join::parse_key(key)).collect();
+           let (left, dialect) = read.open(left)?;
+           let (right, _) = read.open(right)?;
+           let mut writer = write.create(Format::Csv, dialect)?;
+           Join::new(*kind, &on)
+               .duplicates(*duplicates)
+               .suffix(suffix)
+               .memory_limit(spill.memory_limit())
+               .temp_dir(spill.temp_dir())
+               .join(left, right, writer.as_mut())?;
+           Ok(write.file().map(String::from))
+       }
+       Command::GroupBy { input, by, aggregates, write, read } => {
+           let mut group_by = GroupBy::new(by);
+           for aggregate in aggregates {
+               group_by = group_by.aggregate(Aggregate::parse(aggregate)?);
+           }
+           if aggregates.is_empty() {
+               group_by = group_by.aggregate(Aggregate::parse("count")?);
+           }
+           let (reader, dialect) = read.open(input)?;
+           let mut writer = write.create(Format::Csv, dialect)?;
+           group_by.group(reader, writer.as_mut())?;
+           Ok(write.file().map(String::from))
+       }
+       Command::Validate { input, schema, write, read } => {
+           let schema = Schema::load(schema.clone())?;
+           let (reader, dialect) = read.open(input)?;
+           let mut report = Report::create(write, &Violation::HEADERS, dialect)?;
+           let violations = schema.validate(reader, |v| report.add(&v, &[v.to_record()]))?;
+           report.finish()?;
+           if violations > 0 {
+               return Err(Error::Data(format!("{} schema violations", violations)));
+           }
+           Ok(write.file().map(String::from))
+       }
+       Command::Run { pipeline, input, spill, write, read } => {
+           let mut plan = Plan::load(pipeline.clone())?
+               .memory_limit(spill.memory_limit())
+               .temp_dir(spill.temp_dir());
+           let (reader, dialect) = read.open(input)?;
+           let mut writer = write.create(plan.format().unwrap_or(Format::Csv), dialect)?;
+           report(plan.run(reader, writer.as_mut())?);
+           Ok(write.file().map(String::from))
+       }
+       Command::Split { input, rows, bytes, by, prefix, extension, max_open, read } => {
+           let by = match (rows, bytes, by) {
+               (Some(rows), None, None) => SplitBy::Rows(*rows),
+               (None, Some(bytes), None) => SplitBy::Bytes(*bytes),
+               (None, None, Some(column)) => SplitBy::Column(column.clone()),
+               _ => Err("split needs one of --rows, --bytes or --by")?,
+           };
+           let prefix = match prefix {
+               Some(prefix) => prefix.clone(),
+               None if input == "-" => "part".to_string(),
+               None => {
+                   let mut path = Path::new(input).to_path_buf();
+                   if Compression::from_path(input) != Compression::None {
+                       path.set_extension("");
+                   }
+                   path.set_extension("");
+                   path.to_string_lossy().into_owned()
+               }
+           };
+           let (reader, dialect) = read.open(input)?;
+           let files = Split::new(by, &prefix)
+               .extension(extension)
+               .dialect(dialect)
+               .max_open(*max_open)
+               .split(reader)?;
+           for file in &files {
+               println!("{}", file.display());
+           }
+           eprintln!("{} files written", files.len());
+           Ok(None)
+       }
+       Command::Cat { inputs, fill, write, read } => {
+           if inputs.iter().any(|input| input == "-") {
+               Err("cat reads every input twice, so none can be stdin")?
+           }
+           let (_, dialect) = read.open(&inputs[0])?;
+           let mut writer = write.create(Format::Csv, dialect)?;
+           let open = |i: usize| read.open(&inputs[i]).map(|(reader, _)| reader);
+           Cat::new().fill(fill).cat(inputs.len(), open, writer.as_mut())?;
+           Ok(write.file().map(String::from))
+       }
+       Command::Diff { old, new, key, write, read } => {
+           let (old, dialect) = read.open(old)?;
+           let (new, _) = read.open(new)?;
+           let mut report = Report::create(write, &Change::HEADERS, dialect)?;
+           let summary = Diff::new(key).diff(old, new, |c| report.add(&c, &c.to_records()))?;
+           report.finish()?;
+           eprintln!("{}", summary);
+           Ok(write.file().map(String::from))
+       }
+   }
+}
@@ -1,4 +1,21 @@
 use structopt_derive::*;
+use crate::err::Error;
+use crate::core::transform::{Pipeline, Set, Rename, Select, Exclude, Reorder};
+use crate::core::filter::{Filter, Where, When};
+use crate::core::replace::Replacement;
+use crate::core::expr::Compute;
+use crate::core::parse::{Reader, Source};
+use crate::core::read::{input_stream, create_encoded};
+use crate::core::json::JsonReader;
+use crate::core::encoding::Charset;
+use crate::core::format::Output;
+use crate::core::dialect::{parse_char, Dialect, Escape, Terminator, Trim};
+use crate::core::ragged::RaggedPolicy;
+use crate::core::join::{JoinKind, Duplicates};
+use crate::core::format::Format;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "csv_challenge", about = "An example of StructOpt usage.")]
@@ -6,12 +23,524 @@ pub struct Opt {
     /// Needed parameter, the first on the command line.
     // #[structopt(short = "v", long = "verbose")]
     // pub verbosity: u64,
-    #[structopt(help = "Input file")]
-    pub input: String,
+    #[structopt(help = "Input file, - for stdin")]
+    pub input: Option<String>,
     #[structopt(help = "Column Name")]
-    pub column_name: String,
+    pub column_name: Option<String>,
     #[structopt(help = "Replacement Column Name")]
-    pub replacement: String,
-    #[structopt(help = "Output file, stdout if not present")]
+    pub replacement: Option<String>,
+    #[structopt(help = "Output file, stdout if not present or -")]
     pub output: Option<String>,
-}
\ No newline at end of file
+    #[structopt(short = "o", long = "output", help = "Output file, same as the positional one")]
+    pub output_file: Option<String>,
+    #[structopt(long = "output-format", default_value = "csv",
+                help = "csv, tsv, json, ndjson or markdown")]
+    pub output_format: Format,
+    #[structopt(short = "i", long = "in-place", help = "Replace the input file with the output")]
+    pub in_place: bool,
+    #[structopt(long = "backup", help = "Keep the original as <input>.bak when editing in place")]
+    pub backup: bool,
+    #[structopt(short = "j", long = "jobs", default_value = "1",
+                help = "Threads parsing and transforming CSV input, 0 for one per CPU")]
+    pub jobs: usize,
+    #[structopt(long = "where", raw(number_of_values = "1"),
+                help = "Keep only rows matching COLUMN<op>VALUE, op is one of = != < <= > >= ~ !~, \
+                        or COLUMN:empty, COLUMN:nonempty; applied first")]
+    pub filters: Vec<String>,
+    #[structopt(long = "when", raw(number_of_values = "1"),
+                help = "Only replace values in rows matching this filter, same syntax as --where")]
+    pub when: Vec<String>,
+    #[structopt(long = "set", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Replace every value of COLUMN with VALUE, as COLUMN=VALUE")]
+    pub set: Vec<(String, String)>,
+    #[structopt(long = "regex", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Regex find-and-replace inside cells, as COLUMN=/PATTERN/REPLACEMENT/ \
+                        where the first character after `=` is the delimiter and $1 a capture group")]
+    pub regex: Vec<(String, String)>,
+    #[structopt(long = "map", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Map values through a two column CSV of old and new values, as COLUMN=FILE")]
+    pub map: Vec<(String, String)>,
+    #[structopt(long = "template", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Build values from other columns, as COLUMN={City}-{Zip}, applied after the other replacements")]
+    pub template: Vec<(String, String)>,
+    #[structopt(long = "add", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Compute a column from an expression, as COLUMN=EXPR, e.g. total=price*qty, \
+                        added at the end unless it exists; applied after --template")]
+    pub add: Vec<(String, String)>,
+    #[structopt(long = "rename", raw(number_of_values = "1"),
+                parse(try_from_str = "parse_assignment"),
+                help = "Rename a column, as OLD=NEW, applied after --set")]
+    pub rename: Vec<(String, String)>,
+    #[structopt(long = "drop", raw(use_delimiter = "true", number_of_values = "1"),
+                help = "Comma separated columns to remove, applied after --rename")]
+    pub drop: Vec<String>,
+    #[structopt(long = "select", raw(use_delimiter = "true", number_of_values = "1"),
+                help = "Comma separated columns to keep, in that order, applied after --drop")]
+    pub select: Vec<String>,
+    #[structopt(long = "order", raw(use_delimiter = "true", number_of_values = "1"),
+                help = "Comma separated columns to move to the front, applied last")]
+    pub order: Vec<String>,
+    #[structopt(long = "output-encoding",
+                help = "Output encoding, e.g. utf-8, utf-8-bom or gbk, or source for the one of the input; \
+                        utf-8 if not present, source with --in-place")]
+    pub output_encoding: Option<OutputEncoding>,
+    #[structopt(flatten)]
+    pub read: ReadOpt,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The `--output-encoding` of the transform: a charset, or the one the
+/// input was read in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputEncoding {
+    Source,
+    Charset(Charset),
+}
+
+impl FromStr for OutputEncoding {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("source") {
+            return Ok(OutputEncoding::Source);
+        }
+        s.parse().map(OutputEncoding::Charset)
+    }
+}
+
+/// How to read the input, shared by every command.
+#[derive(StructOpt, Debug)]
+pub struct ReadOpt {
+    #[structopt(long = "input-format",
+                help = "csv, tsv, json or ndjson, by default guessed from the input file extension")]
+    pub input_format: Option<Format>,
+    #[structopt(long = "no-header", help = "The input has no header row, address columns as #0 or $1")]
+    pub no_header: bool,
+    #[structopt(long = "headers", raw(use_delimiter = "true", number_of_values = "1"),
+                help = "Comma separated names for an input without header row, written to the output")]
+    pub headers: Vec<String>,
+    #[structopt(short = "d", long = "delimiter", parse(try_from_str = "parse_char"),
+                help = "Field delimiter, e.g. ';' or tab, guessed from the input if not present")]
+    pub delimiter: Option<char>,
+    #[structopt(long = "quote", parse(try_from_str = "parse_char"), help = "Quote character")]
+    pub quote: Option<char>,
+    #[structopt(long = "escape", parse(try_from_str = "parse_char"),
+                help = "Escape character inside quotes, quotes are doubled if not present")]
+    pub escape: Option<char>,
+    #[structopt(long = "terminator", help = "Line terminator: lf, crlf or cr")]
+    pub terminator: Option<Terminator>,
+    #[structopt(long = "trim", help = "Trim whitespace around fields of: none, headers, fields, all")]
+    pub trim: Option<Trim>,
+    #[structopt(long = "ragged", default_value = "fail",
+                help = "Rows with another field count than the header: fail, skip, pad, truncate or fit")]
+    pub ragged: RaggedPolicy,
+    #[structopt(long = "encoding",
+                help = "Input encoding, e.g. utf-8, gbk, gb18030, big5, shift_jis, utf-16le or latin1, \
+                        guessed from the input if not present")]
+    pub encoding: Option<Charset>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(name = "stats",
+                about = "Summarize every column: type, empty and distinct values, min, max, mean, most frequent values")]
+    Stats {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "top", default_value = "5", help = "How many of the most frequent values to show")]
+        top: usize,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "sort", about = "Sort rows by one or more columns, stably, spilling to disk for large inputs")]
+    Sort {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "by", raw(use_delimiter = "true", number_of_values = "1", required = "true"),
+                    help = "Comma separated keys as COLUMN[:num|:lex][:asc|:desc], e.g. Age:num:desc,Name")]
+        by: Vec<String>,
+        #[structopt(flatten)]
+        spill: SpillOpt,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "dedup", about = "Keep the first row of every key, in input order")]
+    Dedup {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "key", raw(use_delimiter = "true", number_of_values = "1"),
+                    help = "Comma separated key columns, all columns if not present")]
+        key: Vec<String>,
+        #[structopt(flatten)]
+        spill: SpillOpt,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "join", about = "Join two files on key columns, in memory when the right one fits")]
+    Join {
+        #[structopt(help = "Left input file, - for stdin")]
+        left: String,
+        #[structopt(help = "Right input file")]
+        right: String,
+        #[structopt(long = "on", raw(use_delimiter = "true", number_of_values = "1", required = "true"),
+                    help = "Comma separated key columns, as COLUMN or LEFT=RIGHT")]
+        on: Vec<String>,
+        #[structopt(long = "kind", default_value = "inner", help = "inner, left or full")]
+        kind: JoinKind,
+        #[structopt(long = "duplicates", default_value = "all",
+                    help = "For keys repeated on the right: all to pair every row, first, or fail")]
+        duplicates: Duplicates,
+        #[structopt(long = "suffix", default_value = "_right",
+                    help = "Appended to right column names already in the left file")]
+        suffix: String,
+        #[structopt(flatten)]
+        spill: SpillOpt,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "group-by", about = "Aggregate the rows of every distinct key, one output row per key")]
+    GroupBy {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "by", raw(use_delimiter = "true", number_of_values = "1", required = "true"),
+                    help = "Comma separated key columns")]
+        by: Vec<String>,
+        #[structopt(long = "agg", raw(use_delimiter = "true", number_of_values = "1"),
+                    help = "Comma separated aggregates as FUNCTION:COLUMN, with count, sum, mean, min, max or distinct, \
+                            e.g. count,mean:Age; count if not present")]
+        aggregates: Vec<String>,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "validate", about = "Check every cell against a TOML schema, failing if any breaks it")]
+    Validate {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "schema", parse(from_os_str), help = "TOML file with a [[column]] table per column: name, type, required, pattern, enum, min, max")]
+        schema: PathBuf,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "run", about = "Run the steps of a TOML pipeline file over an input")]
+    Run {
+        #[structopt(parse(from_os_str),
+                    help = "TOML file with a [[step]] table per step: filter, set, replace, map, template, add, \
+                            rename, drop, select, order or sort")]
+        pipeline: PathBuf,
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(flatten)]
+        spill: SpillOpt,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "split", about = "Split a file into several, by row count, byte size or the value of a column")]
+    Split {
+        #[structopt(help = "Input file, - for stdin")]
+        input: String,
+        #[structopt(long = "rows", help = "Records per file")]
+        rows: Option<u64>,
+        #[structopt(long = "bytes", parse(try_from_str = "parse_size"),
+                    help = "Most bytes per file before compression, header included, with an optional K, M or G suffix")]
+        bytes: Option<u64>,
+        #[structopt(long = "by", help = "Column to split by, one file per distinct value")]
+        by: Option<String>,
+        #[structopt(long = "prefix",
+                    help = "Path the file names start with, the input file name without extension if not present")]
+        prefix: Option<String>,
+        #[structopt(long = "extension", default_value = "csv",
+                    help = "Extension of the files, csv.gz or csv.zst to compress them")]
+        extension: String,
+        #[structopt(long = "max-open", default_value = "64", help = "Most files kept open at once with --by")]
+        max_open: usize,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "cat", about = "Concatenate files, aligning their columns by header name")]
+    Cat {
+        #[structopt(help = "Input files", raw(required = "true"))]
+        inputs: Vec<String>,
+        #[structopt(long = "fill", default_value = "", help = "Value of the columns a file lacks")]
+        fill: String,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+    #[structopt(name = "diff", about = "Show rows added, removed and changed between two files, matched by key")]
+    Diff {
+        #[structopt(help = "Old input file, - for stdin")]
+        old: String,
+        #[structopt(help = "New input file")]
+        new: String,
+        #[structopt(long = "key", raw(use_delimiter = "true", number_of_values = "1", required = "true"),
+                    help = "Comma separated key columns, unique in each file")]
+        key: Vec<String>,
+        #[structopt(flatten)]
+        write: WriteOpt,
+        #[structopt(flatten)]
+        read: ReadOpt,
+    },
+}
+
+/// Where a command writes its result.
+#[derive(StructOpt, Debug)]
+pub struct WriteOpt {
+    #[structopt(short = "o", long = "output", help = "Output file, stdout if not present or -")]
+    pub output: Option<String>,
+    #[structopt(long = "output-format", help = "csv, tsv, json, ndjson or markdown")]
+    pub output_format: Option<Format>,
+    #[structopt(long = "output-encoding", help = "Output encoding, e.g. utf-8-bom or gbk, utf-8 if not present")]
+    pub output_encoding: Option<Charset>,
+}
+
+impl WriteOpt {
+    /// The output file, `None` for stdout.
+    pub fn file(&self) -> Option<&str> {
+        self.output.as_deref().filter(|&o| o != "-")
+    }
+
+    /// Creates the output in `--output-format`, else `format`, and in
+    /// `--output-encoding`.
+    pub fn create(&self, format: Format, dialect: Dialect) -> Result<Box<dyn Output>, Error> {
+        let charset = self.output_encoding.unwrap_or_default();
+        create_encoded(self.file(), self.output_format.unwrap_or(format), dialect, charset)
+    }
+}
+
+/// Memory and disk used by commands that sort.
+#[derive(StructOpt, Debug)]
+pub struct SpillOpt {
+    #[structopt(long = "memory-limit", default_value = "100",
+                help = "MiB of rows kept in memory before sorted runs are spilled to disk")]
+    pub memory_limit: usize,
+    #[structopt(long = "temp-dir", parse(from_os_str),
+                help = "Directory for spill files, the system temporary directory if not present")]
+    pub temp_dir: Option<PathBuf>,
+}
+
+impl SpillOpt {
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit.saturating_mul(1024 * 1024)
+    }
+
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+}
+
+fn parse_assignment(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].to_string(), s[i + 1..].to_string())),
+        None => Err(format!("expected COLUMN=VALUE, found `{}`", s)),
+    }
+}
+
+/// Parses a byte count like `512`, `64K`, `10M` or `1G`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_uppercase();
+    let digits = upper.trim_end_matches('B');
+    let (number, unit) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 1 << 10),
+        Some((i, 'M')) => (&digits[..i], 1 << 20),
+        Some((i, 'G')) => (&digits[..i], 1 << 30),
+        _ => (digits, 1),
+    };
+    match number.trim().parse::<u64>() {
+        Ok(number) => Ok(number.saturating_mul(unit)),
+        Err(_) => Err(format!("expected a size like 512, 64K or 10M, found `{}`", s)),
+    }
+}
+
+/// Splits `/PATTERN/REPLACEMENT/` on its leading delimiter.
+fn parse_substitution(s: &str) -> Result<(&str, &str), Error> {
+    let delimiter = match s.chars().next() {
+        Some(c) => c,
+        None => Err("expected /PATTERN/REPLACEMENT/")?,
+    };
+    let parts: Vec<&str> = s[delimiter.len_utf8()..].split(delimiter).collect();
+    match parts[..] {
+        [pattern, replacement] | [pattern, replacement, ""] => Ok((pattern, replacement)),
+        _ => Err("expected /PATTERN/REPLACEMENT/")?,
+    }
+}
+
+fn parse_filters(exprs: &[String]) -> Result<Vec<Filter>, Error> {
+    exprs.iter().map(|expr| Filter::parse(expr)).collect()
+}
+
+impl ReadOpt {
+    /// Opens `input` in its input format, returning the record source and
+    /// the dialect to write CSV in.
+    pub fn open(&self, input: &str) -> Result<(Box<dyn Source>, Dialect), Error> {
+        let (source, dialect, _) = self.open_encoded(input)?;
+        Ok((source, dialect))
+    }
+
+    /// Like `open`, also returning the charset the input is decoded from.
+    pub fn open_encoded(&self, input: &str) -> Result<(Box<dyn Source>, Dialect, Charset), Error> {
+        match self.format(input)? {
+            Format::Json | Format::Ndjson => {
+                let (stream, name, charset) = input_stream(input, self.encoding)?;
+                let mut reader = JsonReader::new(stream).with_name(name);
+                if input != "-" {
+                    let input = input.to_string();
+                    reader = reader.reopen(move || Ok(input_stream(&input, Some(charset))?.0));
+                }
+                Ok((Box::new(reader), Dialect::default(), charset))
+            }
+            _ => {
+                let (reader, charset) = self.open_csv(input)?;
+                let dialect = reader.dialect().clone();
+                Ok((Box::new(reader), dialect, charset))
+            }
+        }
+    }
+
+    /// Opens `input` as CSV in `--encoding`, else the sniffed one, and
+    /// configures it.
+    pub fn open_csv(&self, input: &str) -> Result<(Reader<Box<dyn BufRead>>, Charset), Error> {
+        let (stream, name, charset) = input_stream(input, self.encoding)?;
+        let reader = Reader::new(stream).with_name(name);
+        Ok((self.configure(reader, input)?, charset))
+    }
+
+    /// Applies the dialect options, `--no-header`, `--headers` and
+    /// `--ragged` to `reader` of `input`, sniffing the dialect when no
+    /// delimiter is given.
+    pub fn configure<R: BufRead>(&self, reader: Reader<R>, input: &str) -> Result<Reader<R>, Error> {
+        let reader = match (self.delimiter, self.format(input)?) {
+            (Some(delimiter), _) => reader.with_dialect(Dialect { delimiter, ..Dialect::default() }),
+            (None, Format::Tsv) => reader.with_dialect(Dialect::tsv()),
+            (None, _) => reader.sniff()?,
+        };
+        let mut dialect = reader.dialect().clone();
+        if let Some(quote) = self.quote {
+            dialect.quote = quote;
+        }
+        if let Some(escape) = self.escape {
+            dialect.escape = Escape::Char(escape);
+        }
+        if let Some(terminator) = self.terminator {
+            dialect.terminator = terminator;
+        }
+        if let Some(trim) = self.trim {
+            dialect.trim = trim;
+        }
+        let reader = reader.with_dialect(dialect).with_ragged(self.ragged);
+        let reader = if !self.headers.is_empty() {
+            reader.with_headers(self.headers.clone())
+        } else if self.no_header {
+            reader.no_header()
+        } else {
+            reader
+        };
+        Ok(reader)
+    }
+
+    /// The `--input-format`, else the one of the `input` file extension,
+    /// else CSV.
+    pub fn format(&self, input: &str) -> Result<Format, Error> {
+        let format = self.input_format.or_else(|| Format::from_path(input));
+        match format.unwrap_or(Format::Csv) {
+            Format::Markdown => Err("markdown can only be written")?,
+            format => Ok(format),
+        }
+    }
+}
+
+impl Opt {
+    /// The input file, required unless a command is given.
+    pub fn input(&self) -> Result<&str, Error> {
+        match &self.input {
+            Some(input) => Ok(input),
+            None => Err("input file missing")?,
+        }
+    }
+
+    /// The charset to write in, given the one of the input.
+    pub fn output_charset(&self, source: Charset) -> Charset {
+        match self.output_encoding {
+            Some(OutputEncoding::Charset(charset)) => charset,
+            Some(OutputEncoding::Source) => source,
+            None if self.in_place => source,
+            None => Charset::default(),
+        }
+    }
+
+    /// The `--jobs` to run, resolving 0 to the number of CPUs.
+    pub fn jobs(&self) -> usize {
+        match self.jobs {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        }
+    }
+
+    /// Builds the transforms requested on the command line, in the order
+    /// where, set, regex, map, template, add, rename, drop, select, order.
+    pub fn pipeline(&self) -> Result<Pipeline, Error> {
+        let mut pipeline = Pipeline::new();
+        if !self.filters.is_empty() {
+            pipeline.push(Where::new(parse_filters(&self.filters)?));
+        }
+        let mut sets = Pipeline::new();
+        match (&self.column_name, &self.replacement) {
+            (Some(column), Some(replacement)) => sets.push(Set::new(column, replacement)),
+            (Some(_), None) => Err("replacement missing for the column name")?,
+            _ => {}
+        }
+        for (column, value) in &self.set {
+            sets.push(Set::new(column, value));
+        }
+        for (column, substitution) in &self.regex {
+            let (pattern, replacement) = parse_substitution(substitution)?;
+            sets.push(Set::with(column, Replacement::regex(pattern, replacement)?));
+        }
+        for (column, file) in &self.map {
+            sets.push(Set::with(column, Replacement::map_file(PathBuf::from(file))?));
+        }
+        for (column, template) in &self.template {
+            sets.push(Set::with(column, Replacement::template(template)?));
+        }
+        if !self.when.is_empty() {
+            pipeline.push(When::new(parse_filters(&self.when)?, sets));
+        } else if !sets.is_empty() {
+            pipeline.push(sets);
+        }
+        for (column, expr) in &self.add {
+            pipeline.push(Compute::new(column, expr)?);
+        }
+        for (from, to) in &self.rename {
+            pipeline.push(Rename::new(from, to));
+        }
+        if !self.drop.is_empty() {
+            pipeline.push(Exclude::new(&self.drop));
+        }
+        if !self.select.is_empty() {
+            pipeline.push(Select::new(&self.select));
+        }
+        if !self.order.is_empty() {
+            pipeline.push(Reorder::new(&self.order));
+        }
+        Ok(pipeline)
+    }
+}
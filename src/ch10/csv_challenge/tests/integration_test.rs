@@ -3,7 +3,6 @@
 mod test {
     use std::path::PathBuf;
     use csv_challenge::{
-        Opt,
         {load_csv, write_csv},
         replace_column,
     };